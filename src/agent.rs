@@ -1,12 +1,21 @@
 use crate::field::CellPos;
-use crate::flowfield::GRID_SIZE;
+use crate::map::LayerId;
 use crate::misc::Vector2;
 use crate::{pathfinding, GRID_CELL_SIZE};
 
+// What an agent can cross: the cost layer of its movement type, and the clearance its
+// footprint needs
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub struct Movement {
+    pub layer: LayerId,
+    pub clearance: u8,
+}
+
 pub struct Agent {
     pub pos: Vector2,
     pub speed: Vector2,
     pub next_dir: Vector2,
+    pub movement: Movement,
 }
 
 impl Agent {
@@ -18,34 +27,25 @@ impl Agent {
             .into()
     }
 
-    pub fn new(pos: Vector2) -> Self {
+    pub fn new(pos: Vector2, movement: Movement) -> Self {
         Agent {
             pos,
             speed: Vector2::new(0.0, 0.0),
             next_dir: Vector2::new(0.0, 0.0),
+            movement,
         }
     }
 
     pub fn follow(&mut self, path: &pathfinding::Result) {
-        let current_zone =
-            pathfinding::Zone::from(self.pos.x / GRID_CELL_SIZE, self.pos.y / GRID_CELL_SIZE);
-
-        let cell_pos_local: CellPos = (
-            (self.pos.x / GRID_CELL_SIZE) as usize - current_zone.min_i(),
-            (self.pos.y / GRID_CELL_SIZE) as usize - current_zone.min_j(),
-        )
-            .into();
-
-        self.next_dir =
-            if let Some(Some(flowfield)) = path.computed.get_safe(&current_zone.large_cell_pos()) {
-                let v = flowfield.flow.get(&cell_pos_local);
-                let x = (v % 3) - 1;
-                let y = (v / 3) - 1;
+        self.next_dir = path
+            .direction(self.pos)
+            .unwrap_or_else(|| Vector2::new(0.0, 0.0));
+    }
 
-                Vector2::new(x as f32, y as f32) * 1.0
-            } else {
-                Vector2::new(0.0, 0.0)
-            }
+    // Brings back an agent that crossed the edge of a wrapped map of this size in pixels
+    pub fn wrap(&mut self, size: f32) {
+        self.pos.x = self.pos.x.rem_euclid(size);
+        self.pos.y = self.pos.y.rem_euclid(size);
     }
 
     pub fn step(&mut self) {
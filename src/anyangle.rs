@@ -0,0 +1,93 @@
+use crate::field::CellPos;
+use crate::source::CostSource;
+
+// Cells crossed by the segment between the centers of two cells, from and to included. Each
+// cell is a neighbor of the previous one, like on an A* path. The same cells both ways, so a
+// segment seen by the search stays clear when walked back from the goal.
+pub fn line(from: &CellPos, to: &CellPos) -> Vec<CellPos> {
+    if (to.i, to.j) < (from.i, from.j) {
+        let mut cells = line(to, from);
+        cells.reverse();
+        return cells;
+    }
+    let (mut i, mut j) = (from.i as i32, from.j as i32);
+    let (ti, tj) = (to.i as i32, to.j as i32);
+    let (di, dj) = ((ti - i).abs(), -(tj - j).abs());
+    let (si, sj) = ((ti - i).signum(), (tj - j).signum());
+    let mut error = di + dj;
+
+    let mut cells = vec![*from];
+    while (i, j) != (ti, tj) {
+        let doubled = 2 * error;
+        if doubled >= dj {
+            error += dj;
+            i += si;
+        }
+        if doubled <= di {
+            error += di;
+            j += sj;
+        }
+        cells.push((i, j).into());
+    }
+    cells
+}
+
+// Cells a search can't go through block the sight like walls
+pub fn line_of_sight(cost: &CostSource, from: &CellPos, to: &CellPos) -> bool {
+    line(from, to)
+        .iter()
+        .all(|cell_pos| cost.contains(cell_pos) && cost.get(cell_pos) != 255)
+}
+
+// Cost of walking the segment, its length times the mean cost of the cells it crosses after
+// the first one. None if a wall blocks the sight.
+pub fn line_cost(cost: &CostSource, from: &CellPos, to: &CellPos) -> Option<i32> {
+    let line = line(from, to);
+    let blocked = |cell_pos: &CellPos| !cost.contains(cell_pos) || cost.get(cell_pos) == 255;
+    if line.iter().any(blocked) {
+        return None;
+    }
+    let crossed: i64 = line[1..]
+        .iter()
+        .map(|cell_pos| cost.get(cell_pos) as i64)
+        .sum();
+    let mean = crossed / (line.len() as i64 - 1).max(1);
+    Some((distance(from, to) as i64 * mean) as i32)
+}
+
+// Euclidean distance, in the unit of CellPos::distance. Never more than the grid moves it
+// replaces, whose diagonals round the square root of 2 down to 1.4.
+pub fn distance(from: &CellPos, to: &CellPos) -> i32 {
+    let di = from.i as f32 - to.i as f32;
+    let dj = from.j as f32 - to.j as f32;
+    let euclidean = ((di * di + dj * dj).sqrt() * 10.0).round() as i32;
+    euclidean.min(from.distance(to))
+}
+
+// String pulling, keeps a waypoint only where the next ones can't be seen from the previous
+// kept one. Only walls block the sight, the cost of the cells crossed is not compared.
+pub fn smooth(cost: &CostSource, path: &[CellPos]) -> Vec<CellPos> {
+    let mut waypoints: Vec<CellPos> = path.iter().take(1).copied().collect();
+    let mut index = 0;
+    while index + 1 < path.len() {
+        let anchor = path[index];
+        let mut furthest = index + 1;
+        while furthest + 1 < path.len() && line_of_sight(cost, &anchor, &path[furthest + 1]) {
+            furthest += 1;
+        }
+        waypoints.push(path[furthest]);
+        index = furthest;
+    }
+    waypoints
+}
+
+// Back to neighboring cells, for the sector flow fields
+pub fn fill(waypoints: &[CellPos]) -> Vec<CellPos> {
+    let mut filled = Vec::with_capacity(waypoints.len());
+    for pair in waypoints.windows(2) {
+        let segment = line(&pair[0], &pair[1]);
+        filled.extend(&segment[..segment.len() - 1]);
+    }
+    filled.extend(waypoints.last());
+    filled
+}
@@ -1,11 +1,22 @@
-use crate::field::{CellPos, Field};
+use crate::anyangle;
+use crate::chunk::Chunks;
+use crate::clearance;
+use crate::directional::{self, DirectionalCost};
+use crate::field::{CellPos, Connectivity, Grid, Topology};
+use crate::jps;
+use crate::source::CostSource;
+use std::cmp::Ordering;
+use std::collections::{BinaryHeap, HashMap};
+use std::time::{Duration, Instant};
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Copy)]
 pub struct AStarNode {
     pub cell_pos: CellPos,
     pub g: i32,
     pub h: i32,
-    pub parent: Option<Box<AStarNode>>,
+    pub parent: Option<CellPos>,
+    // Once expanded, a node is not lowered anymore
+    pub closed: bool,
 }
 
 impl AStarNode {
@@ -18,19 +29,178 @@ impl AStarNode {
     }
 }
 
-#[derive(PartialEq, Eq, Debug, Clone, Copy)]
-pub enum NodeState {
-    Open(i32),
-    Closed,
-    Unknown,
+// Ordered by f, the lowest first out of the open nodes
+impl Ord for AStarNode {
+    fn cmp(&self, other: &Self) -> Ordering {
+        other.f().cmp(&self.f())
+    }
+}
+
+impl PartialOrd for AStarNode {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl PartialEq for AStarNode {
+    fn eq(&self, other: &Self) -> bool {
+        self.f() == other.f()
+    }
+}
+
+impl Eq for AStarNode {}
+
+// Jump point search falls back to A* on maps where it does not hold, see jps::is_uniform.
+// Theta* needs an 8-connected square grid that does not wrap for its line of sight, and no
+// directional cost.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Search {
+    AStar,
+    Jps,
+    ThetaStar,
+    // Also searches from the goal, until both sides meet
+    Bidirectional,
+}
+
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Stats {
+    // Sum of the move costs along the path, in the unit of the g of the nodes
+    pub cost: i32,
+    // Number of cells on the path
+    pub length: usize,
+    // Nodes taken out of the open set, on both sides for a bidirectional search
+    pub expanded: usize,
+    pub elapsed: Duration,
 }
 
 #[derive(Debug)]
 pub struct Result {
     pub from: CellPos,
     pub to: CellPos,
+    // From the goal to the start, empty when the goal can't be reached
     pub path: Vec<CellPos>,
-    pub cost: Field<u8>,
+    // The waypoints Theta* found, the path being filled back to neighboring cells from them
+    pub any_angle: Option<Vec<CellPos>>,
+    pub cost: CostSource,
+    pub directional: Option<DirectionalCost>,
+    pub topology: Topology,
+    pub stats: Stats,
+}
+
+// The side of a bidirectional search that starts from the goal
+#[derive(Debug)]
+pub struct Frontier {
+    pub open_nodes: BinaryHeap<AStarNode>,
+    // Nodes of this side by cell, like the nodes of AStarCompute::Computing
+    nodes: HashMap<CellPos, AStarNode>,
+    expanded: usize,
+    // Cheapest path found where both sides met, with its cost
+    pub best: Option<(i32, Vec<CellPos>)>,
+}
+
+impl Frontier {
+    fn new(start: CellPos, h: i32) -> Frontier {
+        let node = AStarNode {
+            cell_pos: start,
+            g: 0,
+            h,
+            parent: None,
+            closed: false,
+        };
+        Frontier {
+            open_nodes: BinaryHeap::from(vec![node]),
+            nodes: vec![(start, node)].into_iter().collect(),
+            expanded: 0,
+            best: None,
+        }
+    }
+
+    // Called whenever a side reaches a cell for a lower g, g being the cost of both sides
+    fn meet(&mut self, g: i32, start_side: &HashMap<CellPos, AStarNode>, cell_pos: &CellPos) {
+        if self.best.as_ref().is_none_or(|(best, _)| g < *best) {
+            let path = AStarCompute::join(&self.nodes, start_side, cell_pos);
+            self.best = Some((g, path));
+        }
+    }
+
+    // Stops once the next node of either side can't lead to a cheaper meeting, only the tops of
+    // the heaps being looked at. Their g + h is tested rather than their f, which weighs h twice
+    // and would stop too early, and the two sides are not added up, both estimates covering the
+    // whole path. The path kept is the cheapest met though, not always the cheapest there is.
+    fn is_done(
+        &mut self,
+        forward_open_nodes: &mut BinaryHeap<AStarNode>,
+        forward_nodes: &HashMap<CellPos, AStarNode>,
+    ) -> bool {
+        let best = match &self.best {
+            Some((best, _)) => *best,
+            None => return false,
+        };
+        let forward = AStarCompute::peek(forward_open_nodes, forward_nodes);
+        let backward = AStarCompute::peek(&mut self.open_nodes, &self.nodes);
+        let f = |node: Option<AStarNode>| node.map_or(i32::MAX, |n| n.g + n.h);
+        f(forward).max(f(backward)) >= best
+    }
+
+    // Node of this side on a cell the start side reached
+    fn reached(&self, cell_pos: &CellPos) -> Option<&AStarNode> {
+        self.nodes.get(cell_pos)
+    }
+
+    // Edges are walked the other way round, so a move costs what it costs to a unit going
+    // toward the goal
+    fn expand(
+        &mut self,
+        node: &AStarNode,
+        start_side: &HashMap<CellPos, AStarNode>,
+        from: &CellPos,
+        cost: &CostSource,
+        directional: &Option<DirectionalCost>,
+        topology: Topology,
+    ) {
+        let cell_cost = match cost.get(&node.cell_pos) {
+            255 => 255000,
+            x => x as i32,
+        };
+        let (width, height) = (cost.width(), cost.height());
+        for (neighbor_pos, distance) in topology.neighbors(&node.cell_pos, width, height) {
+            if !cost.contains(&neighbor_pos) {
+                continue;
+            }
+            let (di, dj) = directional::step(&neighbor_pos, &node.cell_pos);
+            let extra =
+                directional::extra_cost(directional, &neighbor_pos, di, dj).unwrap_or(255000);
+            let g = node.g + distance as i32 * (cell_cost + extra);
+            if AStarCompute::reached_for(&self.nodes, &neighbor_pos, g) {
+                continue;
+            }
+            let h = topology.distance(from, &neighbor_pos, width, height);
+
+            let neighbor = AStarNode {
+                cell_pos: neighbor_pos,
+                g,
+                h,
+                parent: Some(node.cell_pos),
+                closed: false,
+            };
+            self.nodes.insert(neighbor_pos, neighbor);
+            self.open_nodes.push(neighbor);
+            if let Some(forward) = start_side.get(&neighbor_pos) {
+                self.meet(g + forward.g, start_side, &neighbor_pos);
+            }
+        }
+    }
+}
+
+impl Result {
+    // For a unit following the path itself rather than a flow field. Theta* keeps its own, the
+    // other paths are pulled straight.
+    pub fn waypoints(&self) -> Vec<CellPos> {
+        match &self.any_angle {
+            Some(waypoints) => waypoints.clone(),
+            None => anyangle::smooth(&self.cost, &self.path),
+        }
+    }
 }
 
 #[derive(Debug)]
@@ -38,15 +208,27 @@ pub enum AStarCompute {
     InitialData {
         from: CellPos,
         to: CellPos,
-        cost: Field<u8>,
+        cost: CostSource,
+        directional: Option<DirectionalCost>,
+        topology: Topology,
+        min_clearance: u8,
+        search: Search,
     },
     Computing {
         from: CellPos,
         to: CellPos,
-        cost: Field<u8>,
-        sort_cost: u128,
-        open_nodes: Vec<AStarNode>,
-        nodes_state: Field<NodeState>,
+        cost: CostSource,
+        directional: Option<DirectionalCost>,
+        topology: Topology,
+        // The one actually used, after the fallbacks
+        search: Search,
+        stats: Stats,
+        open_nodes: BinaryHeap<AStarNode>,
+        // Every node reached, open or closed, by cell. An open node reached again more cheaply
+        // is lowered, since a directional cost depends on the move and not on the cell. Its
+        // entry left in the open nodes is skipped, see AStarCompute::pop.
+        nodes: HashMap<CellPos, AStarNode>,
+        backward: Option<Box<Frontier>>,
     },
     Computed(Result),
 }
@@ -60,119 +242,348 @@ impl AStarCompute {
         }
     }
 
-    fn append_parent(astarnode: &AStarNode, path: &mut Vec<CellPos>) {
-        for parent_node in &astarnode.parent {
-            path.push(parent_node.cell_pos.clone());
-            AStarCompute::append_parent(parent_node, path);
+    pub fn is_computed(&self) -> bool {
+        matches!(self, AStarCompute::Computed(_))
+    }
+
+    // Number of nodes expanded by the next call to step
+    pub fn step_cost(&self) -> usize {
+        match self {
+            AStarCompute::Computed(_) => 0,
+            _ => 1,
+        }
+    }
+
+    // So far, while the search runs
+    pub fn stats(&self) -> Stats {
+        match self {
+            AStarCompute::InitialData { .. } => Stats::default(),
+            AStarCompute::Computing { stats, .. } => *stats,
+            AStarCompute::Computed(result) => result.stats,
+        }
+    }
+
+    // Whether the cell was already reached for at most this g, or expanded
+    fn reached_for(nodes: &HashMap<CellPos, AStarNode>, cell_pos: &CellPos, g: i32) -> bool {
+        nodes
+            .get(cell_pos)
+            .is_some_and(|known| known.closed || known.g <= g)
+    }
+
+    // Open node of the lowest f, past the entries of the nodes lowered since they were pushed.
+    // It is closed.
+    fn pop(
+        open_nodes: &mut BinaryHeap<AStarNode>,
+        nodes: &mut HashMap<CellPos, AStarNode>,
+    ) -> Option<AStarNode> {
+        while let Some(node) = open_nodes.pop() {
+            let known = nodes.get_mut(&node.cell_pos).unwrap();
+            if !known.closed && known.g == node.g {
+                known.closed = true;
+                return Some(*known);
+            }
+        }
+        None
+    }
+
+    // Like pop, but the node stays open
+    fn peek(
+        open_nodes: &mut BinaryHeap<AStarNode>,
+        nodes: &HashMap<CellPos, AStarNode>,
+    ) -> Option<AStarNode> {
+        while let Some(node) = open_nodes.peek() {
+            let known = &nodes[&node.cell_pos];
+            if !known.closed && known.g == node.g {
+                return Some(*node);
+            }
+            open_nodes.pop();
         }
+        None
+    }
+
+    // Cells from a node back to where its side started, the node first
+    fn trace(nodes: &HashMap<CellPos, AStarNode>, cell_pos: &CellPos) -> Vec<CellPos> {
+        let mut path = vec![*cell_pos];
+        while let Some(parent) = nodes[path.last().unwrap()].parent {
+            path.push(parent);
+        }
+        path
+    }
+
+    // Path from the goal to the start through a cell both sides of a bidirectional search
+    // reached, goal_side having been searched from the goal
+    fn join(
+        goal_side: &HashMap<CellPos, AStarNode>,
+        start_side: &HashMap<CellPos, AStarNode>,
+        cell_pos: &CellPos,
+    ) -> Vec<CellPos> {
+        let mut path = AStarCompute::trace(goal_side, cell_pos);
+        path.reverse();
+        path.extend(
+            AStarCompute::trace(start_side, cell_pos)
+                .into_iter()
+                .skip(1),
+        );
+        path
     }
 
     pub fn step(self) -> Self {
+        if self.is_computed() {
+            return self;
+        }
+        let start = Instant::now();
+        let mut next = self.advance();
+        match &mut next {
+            AStarCompute::InitialData { .. } => {}
+            AStarCompute::Computing { stats, .. } => stats.elapsed += start.elapsed(),
+            AStarCompute::Computed(result) => result.stats.elapsed += start.elapsed(),
+        }
+        next
+    }
+
+    fn advance(self) -> Self {
         match self {
-            AStarCompute::InitialData { from, to, cost } => {
-                let capacity = (from.distance(&to) / (10 / 2)) as usize;
-                let mut open_nodes = Vec::with_capacity(capacity);
+            AStarCompute::InitialData {
+                from,
+                to,
+                mut cost,
+                directional,
+                topology,
+                min_clearance,
+                search,
+            } => {
+                // Every free cell has a clearance of at least 1. A streamed cost would have to be
+                // loaded whole to measure it, it is searched as is.
+                if let (CostSource::Chunks(chunks), true) = (&mut cost, min_clearance > 1) {
+                    let mut restricted = chunks.to_field();
+                    let clearance = clearance::clearance(&restricted, topology);
+                    clearance::restrict_cost(&mut restricted, &clearance, min_clearance);
+                    chunks.write(&restricted);
+                }
 
-                let (w, h) = (cost.width, cost.height);
+                let search = match search {
+                    Search::Jps if jps::is_uniform(&cost, &directional, topology) => Search::Jps,
+                    Search::ThetaStar
+                        if directional.is_none()
+                            && topology.grid == Grid::Square
+                            && topology.connectivity == Connectivity::Eight
+                            && !topology.wrap =>
+                    {
+                        Search::ThetaStar
+                    }
+                    Search::Bidirectional => Search::Bidirectional,
+                    _ => Search::AStar,
+                };
+
+                let (w, h) = (cost.width(), cost.height());
+                let distance = topology.distance(&from, &to, w, h);
 
-                open_nodes.push(AStarNode {
+                let capacity = (distance / (10 / 2)) as usize;
+                let mut open_nodes = BinaryHeap::with_capacity(capacity);
+
+                let start = AStarNode {
                     cell_pos: from,
                     g: 0,
-                    h: from.distance(&to),
+                    h: distance,
                     parent: None,
-                });
+                    closed: false,
+                };
+                open_nodes.push(start);
+                let backward = if search == Search::Bidirectional {
+                    Some(Box::new(Frontier::new(to, distance)))
+                } else {
+                    None
+                };
                 AStarCompute::Computing {
                     from,
                     to,
                     cost,
-                    sort_cost: 0,
+                    directional,
+                    topology,
+                    search,
+                    stats: Stats::default(),
                     open_nodes,
-                    nodes_state: Field::<NodeState>::new(NodeState::Unknown, w, h),
+                    nodes: vec![(from, start)].into_iter().collect(),
+                    backward,
                 }
             }
             AStarCompute::Computing {
                 from,
                 to,
                 cost,
-                sort_cost,
+                directional,
+                topology,
+                search,
+                mut stats,
                 mut open_nodes,
-                mut nodes_state,
+                mut nodes,
+                mut backward,
             } => {
-                //                let start = std::time::Instant::now();
-                let mut min_node = open_nodes.pop().unwrap();
-                //                let new_sort_cost = sort_cost + start.elapsed().as_micros();
+                if let Some(frontier) = &mut backward {
+                    if frontier.is_done(&mut open_nodes, &nodes) {
+                        let (g, path) = frontier.best.take().unwrap();
+                        stats.cost = g;
+                        stats.length = path.len();
+                        return AStarCompute::Computed(Result {
+                            from,
+                            to,
+                            path,
+                            any_angle: None,
+                            cost,
+                            directional,
+                            topology,
+                            stats,
+                        });
+                    }
+                }
 
-                if to == min_node.cell_pos {
-                    let mut path = Vec::new();
-                    path.push(to);
+                // The side that expanded the fewest nodes goes next
+                let backward_node = match &mut backward {
+                    Some(frontier) if frontier.expanded < stats.expanded - frontier.expanded => {
+                        AStarCompute::pop(&mut frontier.open_nodes, &mut frontier.nodes)
+                    }
+                    _ => None,
+                };
+                if let (Some(node), Some(frontier)) = (backward_node, &mut backward) {
+                    stats.expanded += 1;
+                    frontier.expanded += 1;
+                    frontier.expand(&node, &nodes, &from, &cost, &directional, topology);
+                    return AStarCompute::Computing {
+                        from,
+                        to,
+                        cost,
+                        directional,
+                        topology,
+                        search,
+                        stats,
+                        open_nodes,
+                        nodes,
+                        backward,
+                    };
+                }
+
+                let min_node = match AStarCompute::pop(&mut open_nodes, &mut nodes) {
+                    Some(min_node) => min_node,
+                    // Jump point search runs out of nodes where it does not hold. The search
+                    // starts over as A*, the nodes already expanded still count.
+                    None if search != Search::AStar => {
+                        let start = AStarNode {
+                            cell_pos: from,
+                            g: 0,
+                            h: topology.distance(&from, &to, cost.width(), cost.height()),
+                            parent: None,
+                            closed: false,
+                        };
+                        return AStarCompute::Computing {
+                            from,
+                            to,
+                            cost,
+                            directional,
+                            topology,
+                            search: Search::AStar,
+                            stats,
+                            open_nodes: BinaryHeap::from(vec![start]),
+                            nodes: vec![(from, start)].into_iter().collect(),
+                            backward,
+                        };
+                    }
+                    // Walls are soft for A*, only a corridor that leaves the goal out cuts it off
+                    None => {
+                        return AStarCompute::Computed(Result {
+                            from,
+                            to,
+                            path: Vec::new(),
+                            any_angle: None,
+                            cost,
+                            directional,
+                            topology,
+                            stats,
+                        });
+                    }
+                };
+                stats.expanded += 1;
 
-                    AStarCompute::append_parent(&min_node, &mut path);
+                if to == min_node.cell_pos {
+                    let mut path = AStarCompute::trace(&nodes, &to);
+                    stats.cost = min_node.g;
+                    // Reaching the goal met its side, maybe not on the cheapest path
+                    if let Some(frontier) = &mut backward {
+                        let (g, best) = frontier.best.take().unwrap();
+                        stats.cost = g;
+                        path = best;
+                    }
+                    let any_angle = Some(path.clone()).filter(|_| search == Search::ThetaStar);
+                    // Jump points and any-angle waypoints are not neighbors
+                    if matches!(search, Search::Jps | Search::ThetaStar) {
+                        path = anyangle::fill(&path);
+                    }
+                    stats.length = path.len();
                     AStarCompute::Computed(Result {
                         from,
                         to,
                         path,
+                        any_angle,
                         cost,
+                        directional,
+                        topology,
+                        stats,
                     })
                 } else {
-                    nodes_state.set(&min_node.cell_pos, NodeState::Closed);
-                    let neighbors = crate::field::neighbors_with_distance_iter(
-                        &min_node.cell_pos,
-                        cost.width,
-                        cost.height,
-                    );
+                    let neighbors: Vec<(CellPos, i32)> = if search == Search::Jps {
+                        jps::successors(&cost, &min_node.cell_pos, min_node.parent.as_ref(), &to)
+                    } else {
+                        topology
+                            .neighbors(&min_node.cell_pos, cost.width(), cost.height())
+                            .filter(|(neighbor_pos, _)| cost.contains(neighbor_pos))
+                            .map(|(neighbor_pos, distance)| (neighbor_pos, distance as i32))
+                            .collect()
+                    };
 
+                    // Theta* links the neighbors straight to the parent of the node when it
+                    // can see them, and the line costs less
+                    let grand_parent = min_node
+                        .parent
+                        .filter(|_| search == Search::ThetaStar)
+                        .map(|parent| nodes[&parent]);
                     for (neighbor_pos, neighbor_dist) in neighbors {
-                        match nodes_state.get(&neighbor_pos) {
-                            NodeState::Unknown => {
-                                let g = min_node.g
-                                    + (neighbor_dist as i32
-                                        * match cost.get(&neighbor_pos) {
-                                            255 => 255000,
-                                            x => *x as i32,
-                                        });
-                                let h = to.distance(&neighbor_pos);
-                                let f = AStarNode::f_static(g, h);
-
-                                nodes_state.set(&neighbor_pos, NodeState::Open(f));
-                                let pos = open_nodes
-                                    .binary_search_by(|e| f.cmp(&e.f()))
-                                    .unwrap_or_else(|e| e);
-
-                                let node_to_evaluate = AStarNode {
-                                    cell_pos: neighbor_pos.clone(),
-                                    g,
-                                    h,
-                                    parent: Some(Box::new(min_node.clone())),
-                                };
-                                open_nodes.insert(pos, node_to_evaluate);
+                        let (di, dj) = directional::step(&min_node.cell_pos, &neighbor_pos);
+                        // Like walls, forbidden moves are only avoided
+                        let extra =
+                            directional::extra_cost(&directional, &min_node.cell_pos, di, dj)
+                                .unwrap_or(255000);
+                        // The cost is uniform along a jump, and jumps have no directional cost
+                        let cell_cost = match cost.get(&neighbor_pos) {
+                            255 => 255000,
+                            x => x as i32,
+                        };
+                        let g = min_node.g + neighbor_dist * (cell_cost + extra);
+
+                        let line = grand_parent
+                            .and_then(|parent| {
+                                anyangle::line_cost(&cost, &parent.cell_pos, &neighbor_pos)
+                                    .map(|line_cost| (parent.cell_pos, parent.g + line_cost))
+                            })
+                            // Ties go to the straight line
+                            .filter(|(_, line_g)| *line_g <= g);
+                        let (parent, g) = line.unwrap_or((min_node.cell_pos, g));
+                        if AStarCompute::reached_for(&nodes, &neighbor_pos, g) {
+                            continue;
+                        }
+                        let h = topology.distance(&to, &neighbor_pos, cost.width(), cost.height());
+
+                        let neighbor = AStarNode {
+                            cell_pos: neighbor_pos,
+                            g,
+                            h,
+                            parent: Some(parent),
+                            closed: false,
+                        };
+                        nodes.insert(neighbor_pos, neighbor);
+                        open_nodes.push(neighbor);
+                        if let Some(frontier) = &mut backward {
+                            if let Some(goal_side) = frontier.reached(&neighbor_pos) {
+                                frontier.meet(g + goal_side.g, &nodes, &neighbor_pos);
                             }
-                            //                            NodeState::Open(oldF) => {
-                            //                                let g = min_node.g
-                            //                                    + (neighbor_dist as i32 * cost.get(&neighbor_pos) as i32);
-                            //                                let h = to.distance(&neighbor_pos);
-                            //                                let f = AStarNode::f_static(g, h);
-                            //                                if oldF > f {
-                            //                                    nodes_state.set(&neighbor_pos, NodeState::Open(f));
-                            //                                    let pos_remove = open_nodes
-                            //                                        .iter()
-                            //                                        .position(|e| e.cell_pos == neighbor_pos)
-                            //                                        .unwrap();
-                            //                                    open_nodes.remove(pos_remove);
-                            //
-                            //                                    let node_to_evaluate = AStarNode {
-                            //                                        cell_pos: neighbor_pos.clone(),
-                            //                                        g,
-                            //                                        h,
-                            //                                        parent: Some(Box::new(min_node.clone())),
-                            //                                    };
-                            //
-                            //                                    let pos = open_nodes
-                            //                                        .binary_search_by(|e| f.cmp(&e.f()))
-                            //                                        .unwrap_or_else(|e| e);
-                            //                                    open_nodes.insert(pos, node_to_evaluate);
-                            //                                }
-                            //                            }
-                            _ => {}
                         }
                     }
 
@@ -180,9 +591,13 @@ impl AStarCompute {
                         from,
                         to,
                         cost,
-                        sort_cost: 0, //new_sort_cost,
+                        directional,
+                        topology,
+                        search,
+                        stats,
                         open_nodes,
-                        nodes_state: nodes_state,
+                        nodes,
+                        backward,
                     }
                 }
             }
@@ -197,9 +612,171 @@ impl AStarCompute {
             AStarCompute::InitialData {
                 from: CellPos::new(),
                 to: CellPos::new(),
-                cost: Field::new(0, 0, 0),
+                cost: CostSource::Chunks(Chunks::new(0, 0, 0)),
+                directional: None,
+                topology: Topology::default(),
+                min_clearance: 0,
+                search: Search::AStar,
             },
         );
         *s = old.step();
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::field::Field;
+    use crate::pathfinding::{FullPathCompute, Mode};
+    use crate::world::{NoiseProvider, World};
+    use std::collections::HashSet;
+    use std::sync::{Arc, Mutex};
+
+    fn search(cost: &Field<u8>, from: CellPos, to: CellPos, search: Search) -> Result {
+        let mut astar = AStarCompute::InitialData {
+            from,
+            to,
+            cost: CostSource::Chunks(Chunks::from_field(cost)),
+            directional: None,
+            topology: Topology::default(),
+            min_clearance: 1,
+            search,
+        };
+        while !astar.is_computed() {
+            astar = astar.step();
+        }
+        match astar {
+            AStarCompute::Computed(result) => result,
+            _ => unreachable!(),
+        }
+    }
+
+    #[test]
+    fn searches_agree_on_a_uniform_grid() {
+        let cost = Field::new(1, 64, 64);
+        let (from, to) = ((3, 3).into(), (60, 41).into());
+        for kind in [Search::AStar, Search::Jps, Search::Bidirectional].iter() {
+            let result = search(&cost, from, to, *kind);
+            // 38 diagonal steps, then 19 straight ones
+            assert_eq!(result.stats.cost, 38 * 14 + 19 * 10, "{:?}", kind);
+            assert_eq!(result.stats.length, result.path.len());
+            assert_eq!((result.path[0], *result.path.last().unwrap()), (to, from));
+        }
+    }
+
+    // Two walls to go around, through a gap at the bottom then at the top
+    fn walls() -> Field<u8> {
+        let mut cost = Field::new(1, 64, 64);
+        for j in 0..50 {
+            cost.set(&(20, j).into(), 255);
+            cost.set(&(40, 63 - j).into(), 255);
+        }
+        cost
+    }
+
+    #[test]
+    fn theta_star_cuts_the_corners_of_a_star() {
+        let cost = Field::new(1, 64, 64);
+        let (from, to) = ((3, 3).into(), (60, 41).into());
+        let theta = search(&cost, from, to, Search::ThetaStar);
+        let astar = search(&cost, from, to, Search::AStar);
+        assert!(theta.stats.cost <= astar.stats.cost);
+        // Straight to the goal in the open
+        assert_eq!(theta.any_angle, Some(vec![to, from]));
+        assert_eq!(theta.stats.length, theta.path.len());
+        assert_eq!((theta.path[0], *theta.path.last().unwrap()), (to, from));
+
+        let cost = walls();
+        let (from, to) = ((3, 3).into(), (60, 60).into());
+        let theta = search(&cost, from, to, Search::ThetaStar);
+        assert!(theta.stats.cost <= search(&cost, from, to, Search::AStar).stats.cost);
+    }
+
+    #[test]
+    fn waypoints_see_each_other() {
+        let cost = walls();
+        let (from, to) = ((3, 3).into(), (60, 60).into());
+        let theta = search(&cost, from, to, Search::ThetaStar);
+        let waypoints = theta.waypoints();
+        assert_eq!(Some(&waypoints), theta.any_angle.as_ref());
+        assert_eq!(anyangle::fill(&waypoints), theta.path);
+        assert!(waypoints.len() > 2);
+
+        // The A* path smoothed keeps its ends and never goes through a wall either
+        let astar = search(&cost, from, to, Search::AStar);
+        let smoothed = astar.waypoints();
+        assert_eq!((smoothed[0], *smoothed.last().unwrap()), (to, from));
+        assert!(smoothed.len() < astar.path.len());
+
+        for path in [waypoints, smoothed].iter() {
+            for pair in path.windows(2) {
+                let seen = anyangle::line_of_sight(&theta.cost, &pair[0], &pair[1]);
+                assert!(seen, "{:?} hides {:?}", pair[0], pair[1]);
+            }
+        }
+    }
+
+    #[test]
+    fn cost_is_the_sum_of_the_moves() {
+        let mut cost = Field::new(1, 64, 64);
+        for i in 0..64 {
+            for j in 0..64 {
+                cost.set(&(i, j).into(), 1 + ((i * 7 + j * 13) % 9) as u8);
+            }
+        }
+        let result = search(&cost, (2, 2).into(), (61, 58).into(), Search::AStar);
+        // From the goal to the start, each move costs its length times the cost of the cell
+        // entered
+        let moves: i32 = result
+            .path
+            .windows(2)
+            .map(|pair| {
+                let (di, dj) = directional::step(&pair[1], &pair[0]);
+                let distance = Topology::default().step_distance(di, dj) as i32;
+                distance * *cost.get(&pair[0]) as i32
+            })
+            .sum();
+        assert_eq!(result.stats.cost, moves);
+    }
+
+    #[test]
+    fn a_goal_out_of_the_corridor_has_no_path() {
+        // Only the chunks of the left half can be searched
+        let provider = NoiseProvider { seed: 1 };
+        let world = World::new(64, 64, Topology::default(), 64, Box::new(provider));
+        let world = Arc::new(Mutex::new(world));
+        let corridor: HashSet<CellPos> = (0..4)
+            .flat_map(|i| (0..8).map(move |j| (i, j).into()))
+            .collect();
+        let corridor = Some(Arc::new(corridor));
+        for kind in [Search::AStar, Search::Bidirectional].iter() {
+            let astar = AStarCompute::InitialData {
+                from: (2, 2).into(),
+                to: (60, 60).into(),
+                cost: CostSource::World {
+                    world: world.clone(),
+                    corridor: corridor.clone(),
+                },
+                directional: None,
+                topology: Topology::default(),
+                min_clearance: 1,
+                search: *kind,
+            };
+            let mut compute = FullPathCompute::begin(astar, Mode::Hierarchical);
+            let mut steps = 0;
+            while !compute.is_computed() {
+                compute = compute.step();
+                steps += 1;
+                assert!(steps < 10 * 64 * 64, "{:?} never ends", kind);
+            }
+            match compute {
+                FullPathCompute::FlowFieldComputed(result) => {
+                    assert_eq!(result.stats.astar.length, 0);
+                    assert!(result.stats.flow_cost.is_none());
+                    assert!(result.computed.is_empty());
+                }
+                _ => unreachable!(),
+            }
+        }
+    }
+}
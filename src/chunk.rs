@@ -0,0 +1,223 @@
+use crate::field::{CellPos, Field};
+use crate::flowfield::GRID_SIZE;
+use std::sync::Arc;
+
+// Where a cell is stored
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct LocalCoordinate {
+    pub chunk_x: usize,
+    pub chunk_y: usize,
+    pub local_x: usize,
+    pub local_y: usize,
+}
+
+#[derive(Clone, Debug)]
+pub struct Chunk<T> {
+    pub cells: Field<T>,
+    // Raised each time a cell of the chunk changes
+    pub version: u64,
+}
+
+// A grid stored in tiles of GRID_SIZE cells, the same as the flow field sectors. The chunks on
+// the far edges are partial when the size is not a multiple of GRID_SIZE.
+// Clones share their chunks until one of them changes a chunk, so a path request can keep the
+// cost it started on for the price of the list of chunks.
+#[derive(Clone, Debug)]
+pub struct Chunks<T> {
+    pub width: usize,
+    pub height: usize,
+    pub chunks_on_x: usize,
+    pub chunks_on_y: usize,
+    chunks: Vec<Arc<Chunk<T>>>,
+}
+
+impl<T: Clone + PartialEq> Chunks<T> {
+    pub fn new(value: T, width: usize, height: usize) -> Chunks<T> {
+        Chunks::from_field(&Field::new(value, width, height))
+    }
+
+    pub fn from_field(field: &Field<T>) -> Chunks<T> {
+        let chunks_on_x = field.width.div_ceil(GRID_SIZE);
+        let chunks_on_y = field.height.div_ceil(GRID_SIZE);
+        let mut chunks = Vec::with_capacity(chunks_on_x * chunks_on_y);
+        for chunk_y in 0..chunks_on_y {
+            for chunk_x in 0..chunks_on_x {
+                let min = CellPos {
+                    i: chunk_x * GRID_SIZE,
+                    j: chunk_y * GRID_SIZE,
+                };
+                let view = field.view(&min, GRID_SIZE, GRID_SIZE);
+                chunks.push(Arc::new(Chunk {
+                    cells: Field::from_fn(view.width, view.height, |local| {
+                        view.row(local.j)[local.i].clone()
+                    }),
+                    version: 0,
+                }));
+            }
+        }
+        Chunks {
+            width: field.width,
+            height: field.height,
+            chunks_on_x,
+            chunks_on_y,
+            chunks,
+        }
+    }
+
+    pub fn xy_to_cxcy_lxly(&self, cell_pos: &CellPos) -> LocalCoordinate {
+        LocalCoordinate {
+            chunk_x: cell_pos.i / GRID_SIZE,
+            chunk_y: cell_pos.j / GRID_SIZE,
+            local_x: cell_pos.i % GRID_SIZE,
+            local_y: cell_pos.j % GRID_SIZE,
+        }
+    }
+
+    fn index(&self, chunk: &CellPos) -> usize {
+        chunk.i + chunk.j * self.chunks_on_x
+    }
+
+    pub fn get(&self, cell_pos: &CellPos) -> &T {
+        let lc = self.xy_to_cxcy_lxly(cell_pos);
+        self.chunks[lc.chunk_x + lc.chunk_y * self.chunks_on_x]
+            .cells
+            .get(&(lc.local_x, lc.local_y).into())
+    }
+
+    pub fn set(&mut self, cell_pos: &CellPos, value: T) {
+        let lc = self.xy_to_cxcy_lxly(cell_pos);
+        let index = lc.chunk_x + lc.chunk_y * self.chunks_on_x;
+        let local = (lc.local_x, lc.local_y).into();
+        if *self.chunks[index].cells.get(&local) != value {
+            let chunk = Arc::make_mut(&mut self.chunks[index]);
+            chunk.cells.set(&local, value);
+            chunk.version += 1;
+        }
+    }
+
+    // Whether both hold the very same chunk, not a copy of it
+    #[cfg(test)]
+    pub fn shares_chunk(&self, other: &Chunks<T>, chunk: &CellPos) -> bool {
+        let index = self.index(chunk);
+        Arc::ptr_eq(&self.chunks[index], &other.chunks[index])
+    }
+
+    // Every cell, chunk after chunk
+    pub fn iter(&self) -> impl Iterator<Item = &T> {
+        self.chunks.iter().flat_map(|chunk| chunk.cells.iter())
+    }
+
+    pub fn to_field(&self) -> Field<T> {
+        Field::from_fn(self.width, self.height, |cell_pos| {
+            self.get(&cell_pos).clone()
+        })
+    }
+
+    // Only the chunks that differ from the field are replaced, and count as changed
+    pub fn write(&mut self, field: &Field<T>) {
+        for chunk_y in 0..self.chunks_on_y {
+            for chunk_x in 0..self.chunks_on_x {
+                let min = CellPos {
+                    i: chunk_x * GRID_SIZE,
+                    j: chunk_y * GRID_SIZE,
+                };
+                let index = self.index(&(chunk_x, chunk_y).into());
+                let cells = &self.chunks[index].cells;
+                let view = field.view(&min, cells.width, cells.height);
+                let differs = (0..view.height).any(|j| cells.row(j) != view.row(j));
+                if differs {
+                    let chunk = Arc::make_mut(&mut self.chunks[index]);
+                    for j in 0..view.height {
+                        chunk.cells.row_mut(j).clone_from_slice(view.row(j));
+                    }
+                    chunk.version += 1;
+                }
+            }
+        }
+    }
+
+    // The chunks whose version is not the one seen, and brings the seen versions up to date.
+    // Every chunk has changed for versions seen on another grid, or none.
+    pub fn changed_since(&self, seen: &mut Vec<u64>) -> Vec<CellPos> {
+        let fresh = seen.len() != self.chunks.len();
+        let changed = (0..self.chunks.len())
+            .filter(|index| fresh || seen[*index] != self.chunks[*index].version)
+            .map(|index| (index % self.chunks_on_x, index / self.chunks_on_x).into())
+            .collect();
+        *seen = self.chunks.iter().map(|chunk| chunk.version).collect();
+        changed
+    }
+}
+
+// The chunk holding a cell
+pub fn of(cell_pos: &CellPos) -> CellPos {
+    (cell_pos.i / GRID_SIZE, cell_pos.j / GRID_SIZE).into()
+}
+
+// Cells of the tile of GRID_SIZE cells from min, on a grid of the given size, and of the halo
+// around it, as given by get. None outside of the grid, unless it wraps. The tile of a sector
+// starts before the grid on the odd rows of a hex map, see pathfinding::Zone.
+pub fn sector<T: Clone>(
+    min: (i32, i32),
+    halo: usize,
+    width: usize,
+    height: usize,
+    wrap: bool,
+    mut get: impl FnMut(&CellPos) -> Option<T>,
+) -> Field<Option<T>> {
+    let size = GRID_SIZE + 2 * halo;
+    Field::from_fn(size, size, |local| {
+        let i = min.0 + local.i as i32 - halo as i32;
+        let j = min.1 + local.j as i32 - halo as i32;
+        let (w, h) = (width as i32, height as i32);
+        if wrap {
+            get(&(i.rem_euclid(w), j.rem_euclid(h)).into())
+        } else if i >= 0 && j >= 0 && i < w && j < h {
+            get(&(i, j).into())
+        } else {
+            None
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn changes_bump_only_their_chunk() {
+        // 3x3 chunks, the last row and column partial
+        let mut chunks = Chunks::new(1u8, 20, 20);
+        let mut seen = Vec::new();
+        assert_eq!(chunks.changed_since(&mut seen).len(), 9);
+        assert!(chunks.changed_since(&mut seen).is_empty());
+
+        chunks.set(&(17, 3).into(), 5);
+        chunks.set(&(17, 4).into(), 1);
+        assert_eq!(chunks.changed_since(&mut seen), vec![(2, 0).into()]);
+
+        let mut field = chunks.to_field();
+        field.set(&(3, 19).into(), 9);
+        chunks.write(&field);
+        assert_eq!(chunks.changed_since(&mut seen), vec![(0, 2).into()]);
+        assert_eq!(*chunks.get(&(3, 19).into()), 9);
+
+        // Writing the same cells changes nothing
+        chunks.write(&field);
+        chunks.set(&(0, 0).into(), 1);
+        assert!(chunks.changed_since(&mut seen).is_empty());
+    }
+
+    #[test]
+    fn clones_share_the_chunks_they_have_not_changed() {
+        let chunks = Chunks::new(1u8, 16, 16);
+        let mut copy = chunks.clone();
+        copy.set(&(12, 2).into(), 7);
+        let shared: Vec<bool> = (0..4)
+            .map(|index| Arc::ptr_eq(&chunks.chunks[index], &copy.chunks[index]))
+            .collect();
+        assert_eq!(shared, vec![true, false, true, true]);
+        assert_eq!(*chunks.get(&(12, 2).into()), 1);
+        assert_eq!(*copy.get(&(12, 2).into()), 7);
+    }
+}
@@ -0,0 +1,74 @@
+use crate::field::{CellPos, Field, Topology};
+
+// Distance in cells to the nearest blocked cell, diagonals counting as one. Outside of the map
+// counts as blocked, so a free cell on the border has a clearance of 1, unless the map wraps.
+pub fn clearance(cost: &Field<u8>, topology: Topology) -> Field<u8> {
+    let mut clearance = Field::new(u8::MAX, cost.width, cost.height);
+    let mut to_visit = Vec::new();
+
+    for (cell_pos, cost_value) in cost.cells() {
+        let CellPos { i, j } = cell_pos;
+        let on_border = i == 0 || j == 0 || i == cost.width - 1 || j == cost.height - 1;
+        if *cost_value == 255 {
+            clearance.set(&cell_pos, 0);
+            to_visit.push(cell_pos);
+        } else if on_border && !topology.wrap {
+            clearance.set(&cell_pos, 1);
+            to_visit.push(cell_pos);
+        }
+    }
+
+    while !to_visit.is_empty() {
+        let visiting = std::mem::take(&mut to_visit);
+        for visit in &visiting {
+            let next = clearance.get(visit).saturating_add(1);
+            for (neighbor, _) in topology.neighbors(visit, cost.width, cost.height) {
+                if *clearance.get(&neighbor) > next {
+                    clearance.set(&neighbor, next);
+                    to_visit.push(neighbor);
+                }
+            }
+        }
+    }
+
+    clearance
+}
+
+// A unit needs min_clearance cells around its center, cells too narrow for it become walls
+pub fn restrict_cost(cost: &mut Field<u8>, clearance: &Field<u8>, min_clearance: u8) {
+    for (cost, clearance) in cost.iter_mut().zip(clearance.iter()) {
+        if *clearance < min_clearance {
+            *cost = 255;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn clearance_grows_away_from_the_border() {
+        let cost = Field::new(1, 7, 7);
+        let clearance = clearance(&cost, Topology::default());
+        assert_eq!(*clearance.get(&(0, 3).into()), 1);
+        assert_eq!(*clearance.get(&(1, 1).into()), 2);
+        assert_eq!(*clearance.get(&(3, 3).into()), 4);
+    }
+
+    #[test]
+    fn narrow_corridors_become_walls() {
+        // A corridor one cell wide on row 1, three cells wide on rows 4 to 6
+        let mut cost = Field::new(255, 12, 9);
+        for i in 0..12 {
+            for j in [1, 4, 5, 6].iter() {
+                cost.set(&(i, *j).into(), 1);
+            }
+        }
+        let clearance = clearance(&cost, Topology::default());
+        restrict_cost(&mut cost, &clearance, 2);
+        assert!((0..12).all(|i| *cost.get(&(i, 1).into()) == 255));
+        assert!((1..11).all(|i| *cost.get(&(i, 5).into()) == 1));
+        assert_eq!(*cost.get(&(5, 4).into()), 255);
+    }
+}
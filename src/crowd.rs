@@ -0,0 +1,123 @@
+use crate::agent::Agent;
+use crate::chunk::Chunks;
+use crate::field::{CellPos, Field};
+use crate::GRID_CELL_SIZE;
+
+pub struct Crowd {
+    pub density: Field<f32>,
+    // Cost added to a cell per agent standing on it
+    pub discomfort: f32,
+}
+
+impl Crowd {
+    pub fn new(width: usize, height: usize) -> Crowd {
+        Crowd {
+            density: Field::new(0.0, width, height),
+            discomfort: 8.0,
+        }
+    }
+
+    // Like in continuum crowds, each agent is spread over the 4 cells around it, weighted by
+    // how close it is to their centers
+    pub fn splat(&mut self, agents: &[Agent]) {
+        for density in self.density.iter_mut() {
+            *density = 0.0;
+        }
+
+        for agent in agents {
+            let x = agent.pos.x / GRID_CELL_SIZE - 0.5;
+            let y = agent.pos.y / GRID_CELL_SIZE - 0.5;
+            let (i0, j0) = (x.floor(), y.floor());
+            let (fx, fy) = (x - i0, y - j0);
+
+            let splats = [
+                (0, 0, (1.0 - fx) * (1.0 - fy)),
+                (1, 0, fx * (1.0 - fy)),
+                (0, 1, (1.0 - fx) * fy),
+                (1, 1, fx * fy),
+            ];
+            for (di, dj, weight) in splats.iter() {
+                let (i, j) = (i0 as i32 + di, j0 as i32 + dj);
+                if i >= 0
+                    && j >= 0
+                    && i < self.density.width as i32
+                    && j < self.density.height as i32
+                {
+                    *self.density.get_mut(&CellPos::from((i, j))) += weight;
+                }
+            }
+        }
+    }
+
+    // Walls stay walls, and congestion alone never makes a cell impassable. Only the chunks
+    // where the crowd stands are copied.
+    pub fn congested_cost(&self, cost: &Chunks<u8>) -> Chunks<u8> {
+        let mut congested = cost.clone();
+        for (cell_pos, density) in self.density.cells() {
+            match *cost.get(&cell_pos) {
+                cell_cost if *density > 0.0 && cell_cost != 255 => congested.set(
+                    &cell_pos,
+                    (cell_cost as f32 + density * self.discomfort).min(254.0) as u8,
+                ),
+                _ => {}
+            }
+        }
+        congested
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::agent::Movement;
+    use crate::map::LayerId;
+    use crate::misc::Vector2;
+
+    fn agent_at(x: f32, y: f32) -> Agent {
+        let movement = Movement {
+            layer: LayerId(0),
+            clearance: 1,
+        };
+        Agent::new(Vector2::new(x, y), movement)
+    }
+
+    #[test]
+    fn splat_conserves_the_agents() {
+        let mut crowd = Crowd::new(16, 16);
+        let cell = GRID_CELL_SIZE;
+        let agents = [
+            agent_at(3.5 * cell, 4.5 * cell),
+            agent_at(7.2 * cell, 2.9 * cell),
+            agent_at(10.0 * cell, 10.0 * cell),
+        ];
+        crowd.splat(&agents);
+        let total: f32 = crowd.density.iter().sum();
+        assert!((total - 3.0).abs() < 1e-4, "{}", total);
+        // At the center of a cell, an agent stands on that cell only
+        assert!((*crowd.density.get(&(3, 4).into()) - 1.0).abs() < 1e-4);
+
+        // Splatting again starts over
+        crowd.splat(&agents[..1]);
+        let total: f32 = crowd.density.iter().sum();
+        assert!((total - 1.0).abs() < 1e-4, "{}", total);
+    }
+
+    #[test]
+    fn congestion_copies_only_the_crowded_chunks() {
+        let mut crowd = Crowd::new(32, 32);
+        let cost = Chunks::new(1, 32, 32);
+        crowd.splat(&[agent_at(20.5 * GRID_CELL_SIZE, 3.5 * GRID_CELL_SIZE)]);
+        let congested = crowd.congested_cost(&cost);
+        assert_eq!(*congested.get(&(20, 3).into()), 1 + 8);
+        assert_eq!(*cost.get(&(20, 3).into()), 1);
+        for i in 0..4 {
+            for j in 0..4 {
+                let chunk = (i, j).into();
+                assert_eq!(
+                    congested.shares_chunk(&cost, &chunk),
+                    chunk != (2, 0).into()
+                );
+            }
+        }
+    }
+}
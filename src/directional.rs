@@ -0,0 +1,103 @@
+use crate::field::{CellPos, Field, NEIGHBORS_IJ};
+
+// Extra cost to leave a cell toward each of its neighbors, in the order of NEIGHBORS_IJ
+pub type DirectionalCost = Field<[u8; 8]>;
+
+// Forbids the move, for one-way ramps or conveyor lanes
+pub const FORBIDDEN: u8 = 255;
+
+pub fn direction_index(di: i32, dj: i32) -> usize {
+    NEIGHBORS_IJ.iter().position(|&d| d == (di, dj)).unwrap()
+}
+
+// None if the move from this cell toward (di, dj) is forbidden
+pub fn extra_cost(
+    directional: &Option<DirectionalCost>,
+    from: &CellPos,
+    di: i32,
+    dj: i32,
+) -> Option<i32> {
+    match directional {
+        None => Some(0),
+        Some(directional) => match directional.get(from)[direction_index(di, dj)] {
+            FORBIDDEN => None,
+            extra => Some(extra as i32),
+        },
+    }
+}
+
+// Between neighbors, a longer step can only go through the edge of a wrapped map
+pub fn step(from: &CellPos, to: &CellPos) -> (i32, i32) {
+    let unwrap = |d: i32| match d {
+        d if d > 1 => -1,
+        d if d < -1 => 1,
+        d => d,
+    };
+    (
+        unwrap(to.i as i32 - from.i as i32),
+        unwrap(to.j as i32 - from.j as i32),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::astar::{AStarCompute, Search};
+    use crate::chunk::Chunks;
+    use crate::field::Topology;
+    use crate::source::CostSource;
+
+    #[test]
+    fn extra_cost_of_a_move() {
+        let mut directional = Field::new([0; 8], 4, 4);
+        let cell = CellPos { i: 1, j: 1 };
+        directional.get_mut(&cell)[direction_index(1, 0)] = FORBIDDEN;
+        directional.get_mut(&cell)[direction_index(0, 1)] = 5;
+        let directional = Some(directional);
+
+        assert_eq!(extra_cost(&None, &cell, 1, 0), Some(0));
+        assert_eq!(extra_cost(&directional, &cell, 1, 0), None);
+        assert_eq!(extra_cost(&directional, &cell, 0, 1), Some(5));
+        assert_eq!(extra_cost(&directional, &cell, -1, 0), Some(0));
+    }
+
+    #[test]
+    fn step_through_the_edge() {
+        assert_eq!(step(&(0, 3).into(), &(7, 3).into()), (-1, 0));
+        assert_eq!(step(&(7, 7).into(), &(0, 0).into()), (1, 1));
+        assert_eq!(step(&(2, 2).into(), &(3, 1).into()), (1, -1));
+    }
+
+    #[test]
+    fn astar_avoids_forbidden_moves() {
+        // Nothing can leave the middle column eastward, the path goes around it
+        let mut directional = Field::new([0; 8], 9, 9);
+        for j in 0..8 {
+            for (di, dj) in [(1, -1), (1, 0), (1, 1)].iter() {
+                directional.get_mut(&(4, j).into())[direction_index(*di, *dj)] = FORBIDDEN;
+            }
+        }
+        let mut astar = AStarCompute::InitialData {
+            from: (0, 4).into(),
+            to: (8, 4).into(),
+            cost: CostSource::Chunks(Chunks::new(1, 9, 9)),
+            directional: Some(directional),
+            topology: Topology::default(),
+            min_clearance: 1,
+            search: Search::AStar,
+        };
+        while !astar.is_computed() {
+            astar = astar.step();
+        }
+        let result = match astar {
+            AStarCompute::Computed(result) => result,
+            _ => unreachable!(),
+        };
+        // From the goal to the start
+        for pair in result.path.windows(2) {
+            let (from, (di, _)) = (pair[1], step(&pair[1], &pair[0]));
+            assert!(!(from.i == 4 && from.j < 8 && di == 1));
+        }
+        assert!(result.path.contains(&(4, 8).into()));
+    }
+}
@@ -1,5 +1,3 @@
-use crate::astar::NodeState;
-use std::fmt;
 use std::fmt::{Debug, Formatter};
 
 pub const NEIGHBORS_IJ: [(i32, i32); 8] = [
@@ -27,11 +25,138 @@ impl CellPos {
     pub fn distance(&self, other: &CellPos) -> i32 {
         let di = (self.i as i32 - other.i as i32).abs();
         let dj = (self.j as i32 - other.j as i32).abs();
+        octile(di, dj)
+    }
+}
 
-        if di > dj {
-            10 * (di - dj) + 14 * (dj)
-        } else {
-            10 * (dj - di) + 14 * (di)
+fn octile(di: i32, dj: i32) -> i32 {
+    if di > dj {
+        10 * (di - dj) + 14 * (dj)
+    } else {
+        10 * (dj - di) + 14 * (di)
+    }
+}
+
+// Axial coordinates (q, r) are stored as (i, j), the six neighbors of a hex are the square
+// ones but (-1, -1) and (1, 1)
+pub const HEX_NEIGHBORS_QR: [(i32, i32); 6] = [(0, -1), (1, -1), (-1, 0), (1, 0), (-1, 1), (0, 1)];
+
+// Hex flow directions are numbered after HEX_NEIGHBORS_QR, see Topology::flow_code. Hex sectors
+// are laid like bricks so that each touches six others, see pathfinding::Zone.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub enum Grid {
+    Square,
+    Hex,
+}
+
+// Moves allowed on a square grid, flow directions keep the 3x3 encoding and only lose the
+// diagonal codes with Four
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub enum Connectivity {
+    Four,
+    Eight,
+}
+
+// How the cells of a map connect to each other
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub struct Topology {
+    pub grid: Grid,
+    // Hex grids always have their 6 neighbors
+    pub connectivity: Connectivity,
+    // The right edge touches the left one and the bottom edge the top one
+    pub wrap: bool,
+}
+
+impl Default for Topology {
+    fn default() -> Self {
+        Topology {
+            grid: Grid::Square,
+            connectivity: Connectivity::Eight,
+            wrap: false,
+        }
+    }
+}
+
+impl Topology {
+    pub fn connects(&self, di: i32, dj: i32) -> bool {
+        match self.grid {
+            Grid::Square => match self.connectivity {
+                Connectivity::Four => (di == 0) != (dj == 0),
+                Connectivity::Eight => di != 0 || dj != 0,
+            },
+            Grid::Hex => HEX_NEIGHBORS_QR.contains(&(di, dj)),
+        }
+    }
+
+    // Flow direction of a move. Square grids use the 3x3 codes di + 1 + (dj + 1) * 3, hex ones
+    // the index of the move in HEX_NEIGHBORS_QR.
+    pub fn flow_code(&self, di: i32, dj: i32) -> i8 {
+        match self.grid {
+            Grid::Square => (di + 1 + (dj + 1) * 3) as i8,
+            Grid::Hex => HEX_NEIGHBORS_QR
+                .iter()
+                .position(|&d| d == (di, dj))
+                .unwrap() as i8,
+        }
+    }
+
+    // The code of a cell with no direction, the one left over by the moves
+    pub fn no_flow(&self) -> i8 {
+        match self.grid {
+            Grid::Square => 4,
+            Grid::Hex => HEX_NEIGHBORS_QR.len() as i8,
+        }
+    }
+
+    // Move of a flow direction, None for no_flow
+    pub fn flow_step(&self, code: i8) -> Option<(i32, i32)> {
+        if code == self.no_flow() {
+            return None;
+        }
+        match self.grid {
+            Grid::Square => Some((code as i32 % 3 - 1, code as i32 / 3 - 1)),
+            Grid::Hex => Some(HEX_NEIGHBORS_QR[code as usize]),
+        }
+    }
+
+    pub fn step_distance(&self, di: i32, dj: i32) -> i8 {
+        match self.grid {
+            Grid::Square if di != 0 && dj != 0 => 14,
+            _ => 10,
+        }
+    }
+
+    pub fn neighbors(&self, position: &CellPos, width: usize, height: usize) -> NeighborsIter {
+        NeighborsIter {
+            topology: *self,
+            ..neighbors_with_distance_iter(position, width, height)
+        }
+    }
+
+    // Heuristic distance, a wrapped map can also be crossed through its edges
+    pub fn distance(&self, from: &CellPos, to: &CellPos, width: usize, height: usize) -> i32 {
+        let di = to.i as i32 - from.i as i32;
+        let dj = to.j as i32 - from.j as i32;
+        if !self.wrap {
+            return self.delta_distance(di, dj);
+        }
+        let (w, h) = (width as i32, height as i32);
+        let mut shortest = i32::MAX;
+        for di in [di - w, di, di + w].iter() {
+            for dj in [dj - h, dj, dj + h].iter() {
+                shortest = shortest.min(self.delta_distance(*di, *dj));
+            }
+        }
+        shortest
+    }
+
+    fn delta_distance(&self, di: i32, dj: i32) -> i32 {
+        match self.grid {
+            Grid::Square => match self.connectivity {
+                Connectivity::Four => 10 * (di.abs() + dj.abs()),
+                Connectivity::Eight => octile(di.abs(), dj.abs()),
+            },
+            Grid::Hex => 10 * (di.abs() + dj.abs() + (di + dj).abs()) / 2,
         }
     }
 }
@@ -64,37 +189,40 @@ impl From<(f32, f32)> for CellPos {
 }
 
 #[derive(Clone)]
-pub struct Field<T: Debug> {
+pub struct Field<T> {
     pub arr: Vec<T>,
     pub width: usize,
     pub height: usize,
 }
 
-impl Debug for Field<u8> {
-    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
-        write!(f, "Field of u8")
-    }
-}
-impl Debug for Field<i32> {
+// The cells are left out, a map has tens of thousands of them
+impl<T> Debug for Field<T> {
     fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
-        write!(f, "Field of i32")
+        write!(
+            f,
+            "Field of {} ({}x{})",
+            std::any::type_name::<T>(),
+            self.width,
+            self.height
+        )
     }
 }
-impl Debug for Field<i8> {
-    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
-        write!(f, "Field of i8")
-    }
+
+// Rectangle of a field, in its own coordinates
+pub struct FieldView<'a, T> {
+    field: &'a Field<T>,
+    min: CellPos,
+    pub width: usize,
+    pub height: usize,
 }
 
-impl Debug for Field<bool> {
-    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
-        write!(f, "Field of bool")
+impl<'a, T> FieldView<'a, T> {
+    pub fn row(&self, j: usize) -> &'a [T] {
+        &self.field.row(self.min.j + j)[self.min.i..self.min.i + self.width]
     }
-}
 
-impl Debug for Field<NodeState> {
-    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
-        write!(f, "Field of NodeState")
+    pub fn iter(&self) -> impl Iterator<Item = &'a T> + '_ {
+        (0..self.height).flat_map(move |j| self.row(j))
     }
 }
 
@@ -102,24 +230,22 @@ pub fn neighbors_with_distance(
     position: &CellPos,
     width: usize,
     height: usize,
+    topology: Topology,
 ) -> Vec<(CellPos, i8)> {
-    let mut neighbors: Vec<(CellPos, i8)> = Vec::new();
+    topology.neighbors(position, width, height).collect()
+}
+
+// The cell and its 8 neighbors inside the grid
+pub fn grow(position: &CellPos, width: usize, height: usize) -> Vec<CellPos> {
+    let mut neighbors: Vec<CellPos> = Vec::new();
     let (i0, j0) = (position.i as i32, position.j as i32);
     for di in -1..=1 {
         for dj in -1..=1 {
-            if !(di == 0 && dj == 0)
-                && i0 + di >= 0
-                && j0 + dj >= 0
-                && i0 + di < width as i32
-                && j0 + dj < height as i32
-            {
-                neighbors.push((
-                    CellPos {
-                        i: (i0 + di) as usize,
-                        j: (j0 + dj) as usize,
-                    },
-                    if di != 0 && dj != 0 { 14 } else { 10 },
-                ))
+            if i0 + di >= 0 && j0 + dj >= 0 && i0 + di < width as i32 && j0 + dj < height as i32 {
+                neighbors.push(CellPos {
+                    i: (i0 + di) as usize,
+                    j: (j0 + dj) as usize,
+                })
             }
         }
     }
@@ -132,6 +258,7 @@ pub struct NeighborsIter {
     j0: i32,
     width: usize,
     height: usize,
+    topology: Topology,
 }
 
 impl Iterator for NeighborsIter {
@@ -143,7 +270,17 @@ impl Iterator for NeighborsIter {
         } else {
             let (di, dj) = NEIGHBORS_IJ[self.index];
             self.index += 1;
-            if self.i0 + di >= 0
+            if !self.topology.connects(di, dj) {
+                self.next()
+            } else if self.topology.wrap {
+                Some((
+                    CellPos {
+                        i: (self.i0 + di).rem_euclid(self.width as i32) as usize,
+                        j: (self.j0 + dj).rem_euclid(self.height as i32) as usize,
+                    },
+                    self.topology.step_distance(di, dj),
+                ))
+            } else if self.i0 + di >= 0
                 && self.j0 + dj >= 0
                 && self.i0 + di < self.width as i32
                 && self.j0 + dj < self.height as i32
@@ -153,7 +290,7 @@ impl Iterator for NeighborsIter {
                         i: (self.i0 + di) as usize,
                         j: (self.j0 + dj) as usize,
                     },
-                    if di != 0 && dj != 0 { 14 } else { 10 },
+                    self.topology.step_distance(di, dj),
                 ))
             } else {
                 self.next()
@@ -174,38 +311,104 @@ pub fn neighbors_with_distance_iter(
         j0,
         width,
         height,
+        topology: Topology::default(),
     }
 }
 
-impl<T: Debug> Field<T> {
-    pub fn neighbors_with_distance(&self, position: &CellPos) -> Vec<(CellPos, i8)> {
-        neighbors_with_distance(position, self.width, self.height)
+impl<T> Field<T> {
+    pub fn from_fn(width: usize, height: usize, f: impl FnMut(CellPos) -> T) -> Field<T> {
+        Field {
+            arr: positions(width, height).map(f).collect(),
+            width,
+            height,
+        }
     }
 
-    pub fn grow(&self, position: &CellPos) -> Vec<CellPos> {
-        let mut neighbors: Vec<CellPos> = Vec::new();
-        let (i0, j0) = (position.i as i32, position.j as i32);
-        for di in -1..=1 {
-            for dj in -1..=1 {
-                if i0 + di >= 0
-                    && j0 + dj >= 0
-                    && i0 + di < self.width as i32
-                    && j0 + dj < self.height as i32
-                {
-                    neighbors.push(CellPos {
-                        i: (i0 + di) as usize,
-                        j: (j0 + dj) as usize,
-                    })
-                }
-            }
+    pub fn contains(&self, position: &CellPos) -> bool {
+        position.i < self.width && position.j < self.height
+    }
+
+    // Row by row, like arr
+    pub fn positions(&self) -> impl Iterator<Item = CellPos> {
+        positions(self.width, self.height)
+    }
+
+    pub fn iter(&self) -> std::slice::Iter<'_, T> {
+        self.arr.iter()
+    }
+
+    pub fn iter_mut(&mut self) -> std::slice::IterMut<'_, T> {
+        self.arr.iter_mut()
+    }
+
+    pub fn cells(&self) -> impl Iterator<Item = (CellPos, &T)> {
+        self.positions().zip(&self.arr)
+    }
+
+    pub fn cells_mut(&mut self) -> impl Iterator<Item = (CellPos, &mut T)> {
+        positions(self.width, self.height).zip(&mut self.arr)
+    }
+
+    pub fn map<U>(&self, f: impl FnMut(&T) -> U) -> Field<U> {
+        Field {
+            arr: self.arr.iter().map(f).collect(),
+            width: self.width,
+            height: self.height,
+        }
+    }
+
+    pub fn zip_with<U, V>(&self, other: &Field<U>, mut f: impl FnMut(&T, &U) -> V) -> Field<V> {
+        assert_eq!((self.width, self.height), (other.width, other.height));
+        Field {
+            arr: self
+                .arr
+                .iter()
+                .zip(&other.arr)
+                .map(|(a, b)| f(a, b))
+                .collect(),
+            width: self.width,
+            height: self.height,
+        }
+    }
+
+    pub fn row(&self, j: usize) -> &[T] {
+        &self.arr[j * self.width..(j + 1) * self.width]
+    }
+
+    pub fn row_mut(&mut self, j: usize) -> &mut [T] {
+        &mut self.arr[j * self.width..(j + 1) * self.width]
+    }
+
+    // Clipped to the field, empty if it starts past the field
+    pub fn view(&self, min: &CellPos, width: usize, height: usize) -> FieldView<'_, T> {
+        let min = CellPos {
+            i: min.i.min(self.width),
+            j: min.j.min(self.height),
+        };
+        FieldView {
+            field: self,
+            min,
+            width: width.min(self.width - min.i),
+            height: height.min(self.height - min.j),
         }
-        neighbors
+    }
+
+    pub fn neighbors_with_distance(
+        &self,
+        position: &CellPos,
+        topology: Topology,
+    ) -> Vec<(CellPos, i8)> {
+        neighbors_with_distance(position, self.width, self.height, topology)
     }
 }
 
+fn positions(width: usize, height: usize) -> impl Iterator<Item = CellPos> {
+    (0..height).flat_map(move |j| (0..width).map(move |i| CellPos { i, j }))
+}
+
 impl<T> Field<T>
 where
-    T: Debug + Clone,
+    T: Clone,
 {
     pub fn new(initial: T, width: usize, height: usize) -> Field<T> {
         let total = width * height;
@@ -224,12 +427,30 @@ where
         }
     }
 
+    // Copies a view of another field here, the part falling outside is left out
+    pub fn blit(&mut self, source: &FieldView<'_, T>, at: &CellPos) {
+        let at = CellPos {
+            i: at.i.min(self.width),
+            j: at.j.min(self.height),
+        };
+        let width = source.width.min(self.width - at.i);
+        for j in 0..source.height.min(self.height - at.j) {
+            self.row_mut(at.j + j)[at.i..at.i + width].clone_from_slice(&source.row(j)[..width]);
+        }
+    }
+}
+
+impl<T> Field<T> {
     fn index_of(&self, cell_pos: &CellPos) -> usize {
         cell_pos.i + cell_pos.j * self.width
     }
 
     pub fn get_safe(&self, position: &CellPos) -> Option<&T> {
-        self.arr.get(self.index_of(position))
+        if self.contains(position) {
+            Some(self.get(position))
+        } else {
+            None
+        }
     }
     pub fn get(&self, position: &CellPos) -> &T {
         &self.arr[self.index_of(position)]
@@ -245,3 +466,77 @@ where
         self.arr[index] = v;
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const HEX: Topology = Topology {
+        grid: Grid::Hex,
+        connectivity: Connectivity::Eight,
+        wrap: false,
+    };
+
+    #[test]
+    fn hex_neighbors() {
+        let mut neighbors: Vec<CellPos> = HEX
+            .neighbors(&(3, 3).into(), 8, 8)
+            .map(|(cell, distance)| {
+                assert_eq!(distance, 10);
+                cell
+            })
+            .collect();
+        neighbors.sort_by_key(|cell| (cell.i, cell.j));
+        let expected: Vec<CellPos> = vec![(2, 3), (2, 4), (3, 2), (3, 4), (4, 2), (4, 3)]
+            .into_iter()
+            .map(CellPos::from)
+            .collect();
+        assert_eq!(neighbors, expected);
+
+        // A corner keeps the two moves inside the map, or all six through the edges
+        assert_eq!(HEX.neighbors(&CellPos::new(), 8, 8).count(), 2);
+        let wrapped = Topology { wrap: true, ..HEX };
+        let corner: Vec<CellPos> = wrapped
+            .neighbors(&CellPos::new(), 8, 8)
+            .map(|(cell, _)| cell)
+            .collect();
+        assert_eq!(corner.len(), 6);
+        assert!(corner.contains(&(1, 7).into()));
+        assert!(corner.contains(&(7, 1).into()));
+        assert!(!corner.contains(&(7, 7).into()));
+    }
+
+    #[test]
+    fn hex_distance() {
+        let from = (2, 5).into();
+        assert_eq!(HEX.distance(&from, &(5, 2).into(), 16, 16), 30);
+        assert_eq!(HEX.distance(&from, &(5, 8).into(), 16, 16), 60);
+        assert_eq!(HEX.distance(&from, &(14, 5).into(), 16, 16), 120);
+
+        // Shorter through the edges
+        let wrapped = Topology { wrap: true, ..HEX };
+        assert_eq!(wrapped.distance(&from, &(14, 5).into(), 16, 16), 40);
+        assert_eq!(wrapped.distance(&from, &(3, 14).into(), 16, 16), 70);
+    }
+
+    #[test]
+    fn flow_codes_round_trip() {
+        let square = Topology::default();
+        for topology in [square, HEX].iter() {
+            let mut codes = Vec::new();
+            for (di, dj) in NEIGHBORS_IJ
+                .iter()
+                .filter(|(di, dj)| topology.connects(*di, *dj))
+            {
+                let code = topology.flow_code(*di, *dj);
+                assert_eq!(topology.flow_step(code), Some((*di, *dj)));
+                codes.push(code);
+            }
+            assert!(!codes.contains(&topology.no_flow()));
+            assert_eq!(topology.flow_step(topology.no_flow()), None);
+        }
+        assert_eq!(square.no_flow(), 4);
+        assert_eq!(HEX.no_flow(), 6);
+        assert_eq!(HEX.flow_code(1, -1), 1);
+    }
+}
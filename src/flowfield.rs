@@ -1,11 +1,12 @@
-use crate::field::{CellPos, Field};
-use std::collections::{HashMap, HashSet};
+use crate::directional::{self, DirectionalCost};
+use crate::field::{CellPos, Field, Topology};
 
 pub const MAX_INTEGRATION: i32 = std::i32::MAX / 2; //  GRID_SIZE * 10 * 10 * 10;
 
 pub const GRID_SIZE: usize = 8;
-pub const GRID_SIZE_MINUS: usize = GRID_SIZE - 1;
-pub const CELLS: usize = GRID_SIZE * GRID_SIZE;
+// Cells around a sector holding the integration of its neighbors
+pub const HALO: usize = 1;
+pub const SECTOR_FIELD_SIZE: usize = GRID_SIZE + 2 * HALO;
 
 #[derive(PartialEq, Debug, Clone)]
 pub enum FlowFieldState {
@@ -18,6 +19,8 @@ pub enum FlowFieldState {
 #[derive(Debug, Clone)]
 pub struct FlowField {
     pub cost: Field<u8>,
+    pub directional: Option<DirectionalCost>,
+    pub topology: Topology,
     pub integration: Field<i32>,
     pub flow: Field<i8>,
     pub objective: CellPos,
@@ -28,10 +31,34 @@ pub struct FlowField {
 
 impl FlowField {
     pub fn new(objective: CellPos) -> FlowField {
+        let topology = Topology::default();
         FlowField {
             cost: Field::new(1, GRID_SIZE, GRID_SIZE),
+            directional: None,
+            topology,
             integration: Field::new(MAX_INTEGRATION as i32, GRID_SIZE, GRID_SIZE),
-            flow: Field::new(4, GRID_SIZE, GRID_SIZE),
+            flow: Field::new(topology.no_flow(), GRID_SIZE, GRID_SIZE),
+            objective,
+            to_visit: Vec::new(),
+            state: FlowFieldState::Created,
+            skip_flow: false,
+        }
+    }
+
+    // Sized after the cost, which can cover a whole map
+    pub fn with_cost(
+        objective: CellPos,
+        cost: Field<u8>,
+        directional: Option<DirectionalCost>,
+        topology: Topology,
+    ) -> FlowField {
+        let (width, height) = (cost.width, cost.height);
+        FlowField {
+            cost,
+            directional,
+            topology,
+            integration: Field::new(MAX_INTEGRATION, width, height),
+            flow: Field::new(topology.no_flow(), width, height),
             objective,
             to_visit: Vec::new(),
             state: FlowFieldState::Created,
@@ -54,7 +81,7 @@ impl FlowField {
         match self.state {
             FlowFieldState::Created => {
                 //                self.cost.set(&self.objective, 0);
-                self.integration = Field::new(MAX_INTEGRATION as i32, GRID_SIZE, GRID_SIZE);
+                self.integration = Field::new(MAX_INTEGRATION, self.cost.width, self.cost.height);
                 self.integration.set(&self.objective, 0);
                 self.to_visit = vec![self.objective];
                 self.state = FlowFieldState::ComputingIntegration;
@@ -82,149 +109,134 @@ impl FlowField {
         self.state == FlowFieldState::Ready
     }
 
-    fn step_flow(&mut self) {
-        for i in 0..GRID_SIZE as i32 {
-            for j in 0..GRID_SIZE as i32 {
-                //                if self.flow.get(&(i, j).into()) == 4
-                {
-                    let mut lowest = self.integration.get(&(i, j).into());
-                    let mut dir = 4;
-                    for di in -1..=1 {
-                        for dj in -1..=1 {
-                            if !(di == 0 && dj == 0)
-                                && i + di >= 0
-                                && j + dj >= 0
-                                && i + di < GRID_SIZE as i32
-                                && j + dj < GRID_SIZE as i32
-                            {
-                                let current = self.integration.get(&(i + di, j + dj).into());
-                                if current < lowest {
-                                    lowest = current;
-                                    dir = di + 1 + (dj + 1) * 3;
-                                }
-                            }
-                        }
-                    }
-                    self.flow.set(&(i, j).into(), dir as i8);
+    pub fn step_flow(&mut self) {
+        let (width, height) = (self.cost.width, self.cost.height);
+        for cell_pos in self.cost.positions() {
+            let mut lowest = *self.integration.get(&cell_pos);
+            let mut dir = self.topology.no_flow();
+            for (neighbor, _) in self.topology.neighbors(&cell_pos, width, height) {
+                let (di, dj) = directional::step(&cell_pos, &neighbor);
+                let current = *self.integration.get(&neighbor);
+                if current < lowest && self.allows(cell_pos.i as i32, cell_pos.j as i32, di, dj) {
+                    lowest = current;
+                    dir = self.topology.flow_code(di, dj);
                 }
             }
+            self.flow.set(&cell_pos, dir);
         }
     }
 
-    // neighbors =
-    // [
-    //     (-1,-1),(0,-1),(1,-1),
-    //     ......., self ,......,
-    //     .......,......,......
-    // ]
-    pub fn step_flow_with_neighbors(&mut self, neighbors: &HashMap<(i32, i32), &Box<FlowField>>) {
-        for i in 0..GRID_SIZE as i32 {
-            for j in 0..GRID_SIZE as i32 {
-                //                if self.flow.get(&(i, j).into()) == 4
-                {
-                    let mut lowest = self.integration.get(&(i, j).into());
-                    let mut dir = 4;
-                    let mut count = 0;
-                    for di in -1..=1 {
-                        for dj in -1..=1 {
-                            if !(di == 0 && dj == 0) {
-                                if i + di >= 0
-                                    && j + dj >= 0
-                                    && i + di < GRID_SIZE as i32
-                                    && j + dj < GRID_SIZE as i32
-                                {
-                                    let current = self.integration.get(&(i + di, j + dj).into());
-                                    count += 1;
-                                    if current < lowest {
-                                        lowest = current;
-                                        dir = di + 1 + (dj + 1) * 3;
-                                    }
-                                } else {
-                                    // NEIGHBORHOOD
-
-                                    let needed_neighbor = (
-                                        if i + di < 0 {
-                                            -1
-                                        } else if i + di >= GRID_SIZE as i32 {
-                                            1
-                                        } else {
-                                            0
-                                        },
-                                        if j + dj < 0 {
-                                            -1
-                                        } else if j + dj >= GRID_SIZE as i32 {
-                                            1
-                                        } else {
-                                            0
-                                        },
-                                    );
-
-                                    let factor_i = if (i == 0 && di == -1)
-                                        || (i == GRID_SIZE_MINUS as i32 && di == 1)
-                                    {
-                                        2
-                                    } else {
-                                        1
-                                    };
-                                    let factor_j = if (j == 0 && dj == -1)
-                                        || (j == GRID_SIZE_MINUS as i32 && dj == 1)
-                                    {
-                                        2
-                                    } else {
-                                        1
-                                    };
-                                    let ri =
-                                        (i + di * factor_i + GRID_SIZE as i32) % GRID_SIZE as i32;
-                                    let rj =
-                                        (j + dj * factor_j + GRID_SIZE as i32) % GRID_SIZE as i32;
-
-                                    let neighbor = neighbors.get(&needed_neighbor);
-
-                                    if let (Some(neighbor)) = neighbor {
-                                        let current = neighbor.integration.get(&(ri, rj).into());
-                                        count += 1;
-                                        if current < lowest {
-                                            lowest = current;
-                                            dir = di + 1 + (dj + 1) * 3;
-                                        }
-                                    }
-                                }
-                            }
-                        }
-                    }
-                    if true || count == 8 {
-                        self.flow.set(&(i, j).into(), dir as i8);
-                    } else {
-                        self.flow.set(&(i, j).into(), 4);
-                    }
-                }
-            }
-        }
+    fn allows(&self, i: i32, j: i32, di: i32, dj: i32) -> bool {
+        self.topology.connects(di, dj)
+            && directional::extra_cost(&self.directional, &(i, j).into(), di, dj).is_some()
     }
 
     fn step_integration(&mut self) {
         let to_visit = std::mem::replace(&mut self.to_visit, Vec::new());
 
         for visit in &to_visit {
-            let neighbors = crate::field::neighbors_with_distance(visit, GRID_SIZE, GRID_SIZE);
+            let neighbors = self
+                .topology
+                .neighbors(visit, self.cost.width, self.cost.height);
             let current_integration = *self.integration.get(visit);
 
-            for (neighbor, distance) in &neighbors {
-                let cost_of_neighbor = *self.cost.get(neighbor);
+            for (neighbor, distance) in neighbors {
+                let cost_of_neighbor = *self.cost.get(&neighbor);
+                // Integration goes backward, agents will move from the neighbor to this cell
+                let (di, dj) = directional::step(&neighbor, visit);
+                let extra = directional::extra_cost(&self.directional, &neighbor, di, dj);
 
                 if cost_of_neighbor == 255 {
                     //                    self.integration.set(neighbor, MAX_INTEGRATION);
-                } else {
-                    let old_integration = *self.integration.get(neighbor);
+                } else if let Some(extra) = extra {
+                    let old_integration = *self.integration.get(&neighbor);
                     let new_integration =
-                        current_integration + cost_of_neighbor as i32 * distance.clone() as i32;
+                        current_integration + (cost_of_neighbor as i32 + extra) * distance as i32;
                     if new_integration < MAX_INTEGRATION as i32 && new_integration < old_integration
                     {
-                        self.integration.set(neighbor, new_integration);
-                        self.to_visit.push(neighbor.clone());
+                        self.integration.set(&neighbor, new_integration);
+                        self.to_visit.push(neighbor);
                     }
                 }
             }
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::field::{Connectivity, Grid};
+
+    fn integrate(topology: Topology) -> FlowField {
+        let mut field = FlowField::new(CellPos::new());
+        field.topology = topology;
+        while !field.step() {}
+        field
+    }
+
+    #[test]
+    fn four_connected_flow_has_no_diagonal() {
+        let topology = Topology {
+            connectivity: Connectivity::Four,
+            ..Topology::default()
+        };
+        let field = integrate(topology);
+        let diagonals = [(-1, -1), (1, -1), (-1, 1), (1, 1)];
+        for i in 0..GRID_SIZE {
+            for j in 0..GRID_SIZE {
+                let step = topology.flow_step(*field.flow.get(&(i, j).into()));
+                assert!(!step.is_some_and(|step| diagonals.contains(&step)));
+            }
+        }
+        assert_eq!(*field.integration.get(&(3, 4).into()), 70);
+        assert_eq!(
+            *integrate(Topology::default())
+                .integration
+                .get(&(3, 4).into()),
+            42 + 10
+        );
+    }
+
+    #[test]
+    fn hex_flow_reaches_the_goal() {
+        let topology = Topology {
+            grid: Grid::Hex,
+            ..Topology::default()
+        };
+        // A wall across the field but for its last cell
+        let mut cost = Field::new(1, GRID_SIZE, GRID_SIZE);
+        for i in 0..GRID_SIZE - 1 {
+            cost.set(&(i, 4).into(), 255);
+        }
+        let objective = (1, 1).into();
+        let mut field = FlowField::with_cost(objective, cost, None, topology);
+        while !field.step() {}
+
+        for start in field
+            .cost
+            .positions()
+            .filter(|cell| cell.j != 4 || cell.i == 7)
+        {
+            let mut cell = start;
+            let mut steps = 0;
+            while cell != objective {
+                let code = *field.flow.get(&cell);
+                assert!(code < topology.no_flow(), "no flow at {:?}", cell);
+                let (di, dj) = topology.flow_step(code).unwrap();
+                cell = ((cell.i as i32 + di) as usize, (cell.j as i32 + dj) as usize).into();
+                assert_ne!(*field.cost.get(&cell), 255);
+                steps += 1;
+                assert!(steps < GRID_SIZE * GRID_SIZE, "loop from {:?}", start);
+            }
+            // The walk costs the integration, and on the side of the goal the straight distance
+            let integration = *field.integration.get(&start);
+            assert_eq!(integration, steps as i32 * 10);
+            if start.j < 4 {
+                let distance = topology.distance(&start, &objective, GRID_SIZE, GRID_SIZE);
+                assert_eq!(integration, distance);
+            }
+        }
+        assert_eq!(*field.flow.get(&objective), topology.no_flow());
+    }
+}
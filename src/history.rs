@@ -0,0 +1,255 @@
+use crate::directional::DirectionalCost;
+use crate::field::{CellPos, Field};
+use crate::map::{LayerId, Map};
+use std::collections::VecDeque;
+
+// What a cell of a layer held before and after an edit
+struct CellChange {
+    cell: CellPos,
+    cost: (u8, u8),
+    directional: ([u8; 8], [u8; 8]),
+}
+
+// The height field of the map, if the terrain tool set one
+type Height = Option<Field<f32>>;
+
+// Every cell changed by one stroke, or by one use of a tool
+pub struct Edit {
+    layer: LayerId,
+    changes: Vec<CellChange>,
+    // The height field before and after, when the tool replaced it
+    height: Option<(Height, Height)>,
+}
+
+impl Edit {
+    fn apply(&self, map: &mut Map, undo: bool) {
+        for change in &self.changes {
+            let (cost, directional) = if undo {
+                (change.cost.0, change.directional.0)
+            } else {
+                (change.cost.1, change.directional.1)
+            };
+            map.set_cost(self.layer, &change.cell, cost);
+            map.set_directional_cell(self.layer, &change.cell, directional);
+        }
+        if let Some((before, after)) = &self.height {
+            map.height = if undo { before.clone() } else { after.clone() };
+        }
+    }
+}
+
+// The layer as it was when the stroke began
+struct Snapshot {
+    layer: LayerId,
+    cost: Field<u8>,
+    directional: Option<DirectionalCost>,
+    height: Height,
+}
+
+// Only the changed cells are kept once a stroke ends, the oldest edits are dropped past the
+// capacity
+pub struct History {
+    done: VecDeque<Edit>,
+    undone: Vec<Edit>,
+    stroke: Option<Snapshot>,
+    capacity: usize,
+}
+
+impl History {
+    pub fn new(capacity: usize) -> History {
+        History {
+            done: VecDeque::new(),
+            undone: Vec::new(),
+            stroke: None,
+            capacity,
+        }
+    }
+
+    // Ends the stroke in progress, if any
+    pub fn begin(&mut self, map: &Map, layer: LayerId) {
+        self.end(map);
+        self.stroke = Some(Snapshot {
+            layer,
+            cost: map.cost(layer),
+            directional: map.directional(layer).clone(),
+            height: map.height.clone(),
+        });
+    }
+
+    pub fn is_recording(&self) -> bool {
+        self.stroke.is_some()
+    }
+
+    pub fn end(&mut self, map: &Map) {
+        let snapshot = match self.stroke.take() {
+            Some(snapshot) => snapshot,
+            None => return,
+        };
+        let cost = map.chunks(snapshot.layer);
+        let directional = map.directional(snapshot.layer);
+        let directional_of = |directional: &Option<DirectionalCost>, cell: &CellPos| {
+            directional
+                .as_ref()
+                .map_or([0; 8], |directional| *directional.get(cell))
+        };
+        let changes: Vec<CellChange> = snapshot
+            .cost
+            .cells()
+            .map(|(cell, before)| CellChange {
+                cell,
+                cost: (*before, *cost.get(&cell)),
+                directional: (
+                    directional_of(&snapshot.directional, &cell),
+                    directional_of(directional, &cell),
+                ),
+            })
+            .filter(|change| {
+                change.cost.0 != change.cost.1 || change.directional.0 != change.directional.1
+            })
+            .collect();
+        let same_height = match (&snapshot.height, &map.height) {
+            (Some(before), Some(after)) => before.arr == after.arr,
+            (before, after) => before.is_none() && after.is_none(),
+        };
+        let height = if same_height {
+            None
+        } else {
+            Some((snapshot.height, map.height.clone()))
+        };
+        if changes.is_empty() && height.is_none() {
+            return;
+        }
+
+        self.undone.clear();
+        self.done.push_back(Edit {
+            layer: snapshot.layer,
+            changes,
+            height,
+        });
+        if self.done.len() > self.capacity {
+            self.done.pop_front();
+        }
+    }
+
+    // Returns false when there is nothing to undo
+    pub fn undo(&mut self, map: &mut Map) -> bool {
+        self.end(map);
+        match self.done.pop_back() {
+            Some(edit) => {
+                edit.apply(map, true);
+                self.undone.push(edit);
+                true
+            }
+            None => false,
+        }
+    }
+
+    pub fn redo(&mut self, map: &mut Map) -> bool {
+        self.end(map);
+        match self.undone.pop() {
+            Some(edit) => {
+                edit.apply(map, false);
+                self.done.push_back(edit);
+                true
+            }
+            None => false,
+        }
+    }
+
+    pub fn undo_count(&self) -> usize {
+        self.done.len()
+    }
+
+    pub fn redo_count(&self) -> usize {
+        self.undone.len()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Every cost and directional cost of the layer
+    fn layer(map: &Map, layer: LayerId) -> Vec<(u8, [u8; 8])> {
+        let directional = map.directional(layer);
+        map.cost(layer)
+            .cells()
+            .map(|(cell, cost)| {
+                let extra = directional.as_ref().map_or([0; 8], |d| *d.get(&cell));
+                (*cost, extra)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn undo_and_redo_restore_the_layer() {
+        let mut map = Map::new(16);
+        let ground = LayerId(0);
+        let mut history = History::new(8);
+        let mut states = vec![layer(&map, ground)];
+
+        history.begin(&map, ground);
+        map.set_cost(ground, &(3, 4).into(), 200);
+        map.set_cost(ground, &(12, 9).into(), 255);
+        states.push(layer(&map, ground));
+
+        history.begin(&map, ground);
+        map.set_directional(ground, &(5, 5).into(), 1, 0, 40);
+        map.set_cost(ground, &(3, 4).into(), 7);
+        history.end(&map);
+        states.push(layer(&map, ground));
+        assert_eq!(history.undo_count(), 2);
+
+        for state in states.iter().rev().skip(1) {
+            assert!(history.undo(&mut map));
+            assert_eq!(layer(&map, ground), *state);
+        }
+        assert!(!history.undo(&mut map));
+        for state in states.iter().skip(1) {
+            assert!(history.redo(&mut map));
+            assert_eq!(layer(&map, ground), *state);
+        }
+        assert!(!history.redo(&mut map));
+    }
+
+    #[test]
+    fn an_edit_drops_the_redos() {
+        let mut map = Map::new(16);
+        let ground = LayerId(0);
+        let mut history = History::new(1);
+        for cost in 2..5 {
+            history.begin(&map, ground);
+            map.set_cost(ground, &(1, 1).into(), cost);
+        }
+        history.end(&map);
+        // Past the capacity
+        assert_eq!(history.undo_count(), 1);
+        assert!(history.undo(&mut map));
+        assert_eq!(*map.chunks(ground).get(&(1, 1).into()), 3);
+
+        history.begin(&map, ground);
+        map.set_cost(ground, &(2, 2).into(), 9);
+        history.end(&map);
+        assert_eq!(history.redo_count(), 0);
+    }
+
+    #[test]
+    fn terrain_is_undone_with_its_height() {
+        let mut map = Map::new(16);
+        let ground = LayerId(0);
+        let mut history = History::new(8);
+
+        history.begin(&map, ground);
+        map.height = Some(Field::from_fn(16, 16, |cell| cell.i as f32));
+        map.apply_height(ground, 0.5);
+        history.end(&map);
+        let terrain = layer(&map, ground);
+
+        assert!(history.undo(&mut map));
+        assert!(map.height.is_none());
+        assert_eq!(layer(&map, ground), vec![(1, [0; 8]); 16 * 16]);
+        assert!(history.redo(&mut map));
+        assert_eq!(map.height.as_ref().unwrap().get(&(5, 0).into()), &5.0);
+        assert_eq!(layer(&map, ground), terrain);
+    }
+}
@@ -0,0 +1,123 @@
+use crate::directional::DirectionalCost;
+use crate::field::{CellPos, Topology, NEIGHBORS_IJ};
+use crate::source::CostSource;
+
+// Jump point search only holds when every free cell costs the same, on a bounded square grid
+// with diagonals. A streamed cost would have to be loaded whole to tell.
+pub fn is_uniform(
+    cost: &CostSource,
+    directional: &Option<DirectionalCost>,
+    topology: Topology,
+) -> bool {
+    if directional.is_some() || topology != Topology::default() {
+        return false;
+    }
+    let chunks = match cost {
+        CostSource::Chunks(chunks) => chunks,
+        _ => return false,
+    };
+    let mut free = chunks.iter().filter(|cost| **cost != 255);
+    match free.next() {
+        Some(first) => free.all(|cost| cost == first),
+        None => false,
+    }
+}
+
+fn walkable(cost: &CostSource, i: i32, j: i32) -> bool {
+    i >= 0
+        && j >= 0
+        && i < cost.width() as i32
+        && j < cost.height() as i32
+        && cost.get(&(i, j).into()) != 255
+}
+
+fn has_forced_neighbor(cost: &CostSource, i: i32, j: i32, di: i32, dj: i32) -> bool {
+    if di != 0 && dj != 0 {
+        (!walkable(cost, i - di, j) && walkable(cost, i - di, j + dj))
+            || (!walkable(cost, i, j - dj) && walkable(cost, i + di, j - dj))
+    } else if di != 0 {
+        (!walkable(cost, i, j + 1) && walkable(cost, i + di, j + 1))
+            || (!walkable(cost, i, j - 1) && walkable(cost, i + di, j - 1))
+    } else {
+        (!walkable(cost, i + 1, j) && walkable(cost, i + 1, j + dj))
+            || (!walkable(cost, i - 1, j) && walkable(cost, i - 1, j + dj))
+    }
+}
+
+// Directions worth exploring from a cell reached by moving along (di, dj), the other
+// neighbors are reached at least as cheaply without going through this cell
+fn pruned_directions(cost: &CostSource, i: i32, j: i32, di: i32, dj: i32) -> Vec<(i32, i32)> {
+    let mut directions = Vec::new();
+    if di != 0 && dj != 0 {
+        directions.extend(&[(di, 0), (0, dj), (di, dj)]);
+        if !walkable(cost, i - di, j) {
+            directions.push((-di, dj));
+        }
+        if !walkable(cost, i, j - dj) {
+            directions.push((di, -dj));
+        }
+    } else if di != 0 {
+        directions.push((di, 0));
+        if !walkable(cost, i, j + 1) {
+            directions.push((di, 1));
+        }
+        if !walkable(cost, i, j - 1) {
+            directions.push((di, -1));
+        }
+    } else {
+        directions.push((0, dj));
+        if !walkable(cost, i + 1, j) {
+            directions.push((1, dj));
+        }
+        if !walkable(cost, i - 1, j) {
+            directions.push((-1, dj));
+        }
+    }
+    directions
+}
+
+fn jump(cost: &CostSource, from: &CellPos, di: i32, dj: i32, to: &CellPos) -> Option<CellPos> {
+    let (mut i, mut j) = (from.i as i32, from.j as i32);
+    loop {
+        i += di;
+        j += dj;
+        if !walkable(cost, i, j) {
+            return None;
+        }
+        let cell_pos: CellPos = (i, j).into();
+        if cell_pos == *to || has_forced_neighbor(cost, i, j, di, dj) {
+            return Some(cell_pos);
+        }
+        if di != 0
+            && dj != 0
+            && (jump(cost, &cell_pos, di, 0, to).is_some()
+                || jump(cost, &cell_pos, 0, dj, to).is_some())
+        {
+            return Some(cell_pos);
+        }
+    }
+}
+
+// Jump points reachable from a node, with their distance in the same unit as the A* neighbors
+pub fn successors(
+    cost: &CostSource,
+    node: &CellPos,
+    parent: Option<&CellPos>,
+    to: &CellPos,
+) -> Vec<(CellPos, i32)> {
+    let directions = match parent {
+        None => NEIGHBORS_IJ.to_vec(),
+        Some(parent) => pruned_directions(
+            cost,
+            node.i as i32,
+            node.j as i32,
+            (node.i as i32 - parent.i as i32).signum(),
+            (node.j as i32 - parent.j as i32).signum(),
+        ),
+    };
+    directions
+        .into_iter()
+        .filter_map(|(di, dj)| jump(cost, node, di, dj, to))
+        .map(|point| (point, node.distance(&point)))
+        .collect()
+}
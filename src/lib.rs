@@ -4,85 +4,221 @@ use ggez::graphics::{self, Rect};
 use ggez::nalgebra as na;
 use ggez::{Context, GameResult};
 mod agent;
+mod anyangle;
 mod astar;
+mod chunk;
+mod clearance;
+mod crowd;
+mod directional;
 mod field;
 mod flowfield;
+mod history;
 mod imgui_wrapper;
+mod jps;
 mod map;
+mod mapgen;
 mod misc;
+mod paint;
 mod pathfinding;
+mod source;
 mod sprite;
+mod terrain;
 mod ui_impl;
-use crate::agent::Agent;
+mod validation;
+mod worker;
+mod world;
+use crate::agent::{Agent, Movement};
 use crate::astar::AStarCompute;
-use crate::field::{CellPos, Field};
-use crate::flowfield::{FlowField, FlowFieldState, GRID_SIZE};
-use crate::map::Map;
+use crate::crowd::Crowd;
+use crate::field::{CellPos, Connectivity, Field, Grid, Topology};
+use crate::flowfield::{FlowField, GRID_SIZE, HALO};
+use crate::history::History;
+use crate::map::{LayerId, Map};
 use crate::misc::Vector2;
-use crate::pathfinding::{FullPathCompute, PathComputer, Zone};
+use crate::paint::{Brush, Tool};
+use crate::pathfinding::{Budget, FullPathCompute, PathComputer, RequestId, Zone};
+use crate::validation::Report;
+use imgui::ImString;
 use imgui_wrapper::ImGuiWrapper;
-use rand::Rng;
+use rand::prelude::StdRng;
+use rand::{Rng, SeedableRng};
 use sprite::AllSprite;
-use std::collections::HashSet;
-use ui_impl::HighLevelUI;
-
+use std::collections::{HashMap, HashSet};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use ui_impl::{HighLevelUI, PathEntry};
+use worker::{JobHandle, JobStatus, PathWorker};
+use world::{NoiseProvider, Progress, World};
+
+// A multiple of GRID_SIZE, so the sectors also cover the map when it wraps
 const MAP_SIZE: usize = 256;
 const GRID_CELL_SIZE: f32 = 8.0;
+// Edits that can be undone
+const HISTORY_SIZE: usize = 100;
+// Above the exact cost to the goal, a flow walk is counted as suboptimal
+const SUBOPTIMAL_TOLERANCE: f32 = 0.05;
+// The streaming world, far too large to hold, and its share of each frame
+const WORLD_SIZE: usize = 16384;
+const WORLD_FRAME_TIME: Duration = Duration::from_millis(8);
+
+// A path across the streaming world, and the agent walking it once it is computed
+struct Crossing {
+    world: Arc<Mutex<World>>,
+    id: RequestId,
+    // Set while the path is computed on the background worker
+    job: Option<JobHandle>,
+    walker: Option<Agent>,
+}
 
 pub struct MainState {
     imgui_wrapper: ImGuiWrapper,
     hidpi_factor: f32,
     map: Map,
+    history: History,
     sprite: AllSprite,
     path_computer: PathComputer,
+    path_worker: PathWorker,
+    path_jobs: Vec<(Movement, JobHandle)>,
+    followed_paths: HashMap<Movement, RequestId>,
+    trips: HashMap<Movement, (CellPos, CellPos)>,
+    repaths: HashMap<Movement, RequestId>,
+    repath_ticks: i32,
+    // Versions of the chunks of each layer at the last update
+    seen_chunks: HashMap<LayerId, Vec<u64>>,
+    last_drawn_cell: Option<CellPos>,
+    // Where the line or rectangle being dragged starts, and with which button
+    shape_start: Option<(CellPos, MouseButton)>,
+    crowd: Crowd,
     start: CellPos,
     agents: Vec<Agent>,
+    // Apart from the requests on the map, which are drawn over it
+    world_computer: PathComputer,
+    crossing: Option<Crossing>,
 }
 
 impl EventHandler for MainState {
     fn update(&mut self, _ctx: &mut Context) -> GameResult {
-        if self.ui().set_demo {
-            self.ui_mut().set_demo = false;
-            let field = MainState::create_demo_cost_field();
-            for (i, v) in self.map.cost.arr.iter_mut().zip(field.arr) {
-                *i = (v * 255.0).min(255.0).max(1.0) as u8;
-            }
+        if self.ui().generate {
+            self.ui_mut().generate = false;
+            let generator = mapgen::GENERATORS[self.ui().generator];
+            let seed = self.ui().seed as u64;
+            let layer = self.layer();
+            let size = self.map.size;
+            self.record_edit(layer, |map| {
+                map.set_cost_field(layer, &generator.generate(seed, size, size))
+            });
+        }
+
+        if self.ui().set_terrain {
+            self.ui_mut().set_terrain = false;
+            let layer = self.layer();
+            let max_slope = self.ui().max_slope;
+            let height = MainState::create_demo_height_field(self.ui().seed as u64);
+            self.record_edit(layer, |map| {
+                map.height = Some(height);
+                map.apply_height(layer, max_slope)
+            });
+        }
+
+        if self.ui().undo {
+            self.ui_mut().undo = false;
+            self.history.undo(&mut self.map);
+        }
+        if self.ui().redo {
+            self.ui_mut().redo = false;
+            self.history.redo(&mut self.map);
         }
 
         self.ui_mut().number_of_agent = self.agents.len();
+        self.ui_mut().undo_count = self.history.undo_count();
+        self.ui_mut().redo_count = self.history.redo_count();
+
+        let topology = Topology {
+            grid: if self.ui().hex_grid {
+                Grid::Hex
+            } else {
+                Grid::Square
+            },
+            connectivity: if self.ui().diagonal_moves {
+                Connectivity::Eight
+            } else {
+                Connectivity::Four
+            },
+            wrap: self.ui().wrap,
+        };
+        if topology != self.map.topology {
+            // Paths computed on the other topology are no longer valid
+            self.map.topology = topology;
+            self.path_computer.clear();
+            self.cancel_background(None);
+            self.trips.clear();
+            self.repaths.clear();
+        }
+
+        self.path_computer.search = self.ui().search;
+        self.path_worker.search = self.ui().search;
+        self.path_computer.mode = self.ui().mode;
+        self.path_worker.mode = self.ui().mode;
 
         for agent in &mut self.agents {
             agent.step();
+            if self.map.topology.wrap {
+                agent.wrap(self.map.size as f32 * GRID_CELL_SIZE);
+            }
         }
 
-        for pathfinding in self
-            .path_computer
-            .full_paths
-            .iter()
-            .filter_map(|p| match p {
-                FullPathCompute::FlowFieldComputed(r) => Some(r),
-                _ => None,
-            })
-            .next()
-        {
-            for agent in &mut self.agents {
+        self.repath_edits();
+        self.crowd.splat(&self.agents);
+        if self.ui().crowd_avoidance {
+            self.crowd.discomfort = self.ui().discomfort;
+            self.repath_crowds();
+        }
+        self.swap_repaths();
+
+        let (path_computer, followed_paths) = (&self.path_computer, &self.followed_paths);
+        for agent in &mut self.agents {
+            if let Some(pathfinding) = followed_paths
+                .get(&agent.movement)
+                .and_then(|id| path_computer.full_path_result(*id))
+            {
                 agent.follow(pathfinding);
             }
         }
 
-        let bools: Vec<bool> = self.ui().full_pathfinding.iter().map(|x| x.0).collect();
-
-        for (index, to_delete) in bools.iter().enumerate() {
-            if *to_delete {
-                self.path_computer.full_paths.remove(index);
+        for entry in self.ui().full_pathfinding.clone() {
+            if entry.delete {
+                self.cancel(entry.id);
+            } else if entry.prioritize {
+                self.set_priority(entry.id, entry.priority + 1);
             }
         }
 
+        let background = self.path_jobs.iter().map(|(_, job)| PathEntry {
+            id: job.id(),
+            priority: job.priority(),
+            computed: false,
+            background: true,
+            stats: None,
+            delete: false,
+            prioritize: false,
+        });
         self.ui_mut().full_pathfinding = self
             .path_computer
             .full_paths
             .iter()
-            .map(|e| (false, format!("Pathfinding")))
+            .map(|e| PathEntry {
+                id: e.id,
+                priority: e.priority,
+                computed: self.path_computer.is_complete(e.id) == Some(true),
+                background: false,
+                stats: self
+                    .path_computer
+                    .full_path_result(e.id)
+                    .map(|result| result.stats),
+                delete: false,
+                prioritize: false,
+            })
+            .chain(background)
             .collect();
 
         if self.ui().compute_all {
@@ -90,9 +226,7 @@ impl EventHandler for MainState {
         }
 
         if self.ui().compute_live {
-            for _ in 0..self.ui().step_per_frame {
-                self.compute_live();
-            }
+            self.compute_live();
         }
 
         if self.ui().compute_step {
@@ -100,6 +234,9 @@ impl EventHandler for MainState {
             self.compute_step();
         }
 
+        self.poll_background();
+        self.cross_world();
+
         self.ui_mut().zoom_smooth = self.ui().zoom * 0.1 + self.ui().zoom_smooth * 0.9;
         self.ui_mut().cam_pos_smooth = self.ui().cam_pos * 0.1 + self.ui().cam_pos_smooth * 0.9;
 
@@ -107,6 +244,17 @@ impl EventHandler for MainState {
         self.ui_mut().mouse_pos_camera = -self.ui_mut().cam_pos_smooth
             + (self.ui_mut().mouse_pos - half_screen) / self.ui_mut().zoom_smooth;
 
+        let cost_to_goal = self
+            .followed_result()
+            .and_then(|result| result.cost_to_goal(self.ui().mouse_pos_camera));
+        self.ui_mut().cost_to_goal = cost_to_goal;
+
+        if self.ui().validate_flow {
+            self.ui_mut().validate_flow = false;
+            let report = self.validate_flow();
+            self.ui_mut().flow_report = report;
+        }
+
         let zoom_move_mult = 1.0 / self.ui().zoom_smooth;
 
         let shift_mult = if self.ui_mut().keys_pressed.contains(&KeyCode::LShift) {
@@ -123,8 +271,18 @@ impl EventHandler for MainState {
                 .min(self.map.size - 1),
         };
 
+        let control = self.ui().keys_pressed.contains(&KeyCode::LControl);
+        if control && self.ui().keys_triggered.contains(&KeyCode::Z) {
+            self.history.undo(&mut self.map);
+        }
+        if control && self.ui().keys_triggered.contains(&KeyCode::Y) {
+            self.history.redo(&mut self.map);
+        }
+
         for key_pressed in self.ui_mut().keys_pressed.clone() {
             match key_pressed {
+                // Ctrl+Z undoes rather than pans
+                _ if control => {}
                 KeyCode::Z => {
                     self.ui_mut().cam_pos += Vector2::new(0.0, 1.0) * shift_mult * zoom_move_mult
                 }
@@ -142,7 +300,8 @@ impl EventHandler for MainState {
             }
         }
 
-        let big_cell_pos = self.map.cost.grow(&cell_pos);
+        let layer = self.layer();
+        let big_cell_pos = field::grow(&cell_pos, self.map.size, self.map.size);
 
         let mouse_triggered_or_pressed = self.ui().get_mouse_pressed_or_triggered();
 
@@ -152,20 +311,26 @@ impl EventHandler for MainState {
         if !self.imgui_wrapper.imgui.io().want_capture_mouse {
             if self.ui().keys_triggered.contains(&KeyCode::Space) {
                 if self.ui().keys_pressed.contains(&KeyCode::LControl) {
-                    self.agents.push(Agent::new(Vector2::new(
-                        cell_pos.i as f32 * GRID_CELL_SIZE,
-                        cell_pos.j as f32 * GRID_CELL_SIZE,
-                    )));
+                    self.agents.push(Agent::new(
+                        Vector2::new(
+                            cell_pos.i as f32 * GRID_CELL_SIZE,
+                            cell_pos.j as f32 * GRID_CELL_SIZE,
+                        ),
+                        self.movement(),
+                    ));
                 } else {
                     let mut rng = rand::prelude::thread_rng();
                     for _ in 0..250 {
                         let x: f32 = rng.gen_range(-GRID_CELL_SIZE * 10.0, GRID_CELL_SIZE * 10.0);
                         let y: f32 = rng.gen_range(-GRID_CELL_SIZE * 10.0, GRID_CELL_SIZE * 10.0);
 
-                        self.agents.push(Agent::new(Vector2::new(
-                            cell_pos.i as f32 * GRID_CELL_SIZE + x,
-                            cell_pos.j as f32 * GRID_CELL_SIZE + y,
-                        )));
+                        self.agents.push(Agent::new(
+                            Vector2::new(
+                                cell_pos.i as f32 * GRID_CELL_SIZE + x,
+                                cell_pos.j as f32 * GRID_CELL_SIZE + y,
+                            ),
+                            self.movement(),
+                        ));
                     }
                 }
             }
@@ -173,21 +338,90 @@ impl EventHandler for MainState {
                 self.agents.clear();
             }
 
+            // A stroke is a single edit, from the press of a drawing button to its release
+            let drawing = self.ui().cursor_control != ui_impl::CursorControl::TripSetting
+                && (mouse_triggered_or_pressed.contains(&MouseButton::Left)
+                    || mouse_triggered_or_pressed.contains(&MouseButton::Right));
+            if drawing && !self.history.is_recording() {
+                self.history.begin(&self.map, layer);
+            }
+
             match self.ui().cursor_control {
                 ui_impl::CursorControl::CostDrawing => {
+                    let pressed = [MouseButton::Left, MouseButton::Right]
+                        .iter()
+                        .copied()
+                        .find(|button| mouse_triggered_or_pressed.contains(button));
+                    let tool = self.ui().tool;
+                    match tool {
+                        Tool::Brush => match pressed {
+                            Some(button) => {
+                                // From the last cell, so a fast stroke leaves no gap
+                                let from = self.last_drawn_cell.unwrap_or(cell_pos);
+                                let brush = self.brush(button);
+                                self.map
+                                    .edit_cost(layer, |cost| brush.line(cost, &from, &cell_pos));
+                                self.last_drawn_cell = Some(cell_pos);
+                            }
+                            None => self.last_drawn_cell = None,
+                        },
+                        Tool::Line | Tool::Rectangle => match (self.shape_start, pressed) {
+                            (None, Some(button)) => self.shape_start = Some((cell_pos, button)),
+                            (Some((start, button)), None) => {
+                                self.shape_start = None;
+                                let brush = self.brush(button);
+                                self.map.edit_cost(layer, |cost| {
+                                    if tool == Tool::Line {
+                                        brush.line(cost, &start, &cell_pos);
+                                    } else {
+                                        paint::rectangle(cost, &start, &cell_pos, brush.value);
+                                    }
+                                });
+                            }
+                            _ => {}
+                        },
+                        Tool::Fill => {
+                            let triggered = [MouseButton::Left, MouseButton::Right]
+                                .iter()
+                                .copied()
+                                .find(|button| mouse_triggered.contains(button));
+                            if let Some(button) = triggered {
+                                let value = self.brush(button).value;
+                                let topology = self.map.topology;
+                                self.map.edit_cost(layer, |cost| {
+                                    paint::flood_fill(cost, &cell_pos, value, topology)
+                                });
+                            }
+                        }
+                    }
+                    if mouse_triggered.contains(&MouseButton::Middle) {
+                        self.record_edit(layer, |map| map.reset(layer));
+                    }
+                }
+                ui_impl::CursorControl::OneWayDrawing => {
                     if mouse_triggered_or_pressed.contains(&MouseButton::Left) {
-                        for cell_pos in &big_cell_pos {
-                            self.map.cost.set(&cell_pos, std::u8::MAX);
+                        if let Some(last) = self.last_drawn_cell.filter(|last| *last != cell_pos) {
+                            let di = (cell_pos.i as i32 - last.i as i32).signum();
+                            let dj = (cell_pos.j as i32 - last.j as i32).signum();
+                            for cell_pos in &big_cell_pos {
+                                self.map.set_directional(
+                                    layer,
+                                    cell_pos,
+                                    -di,
+                                    -dj,
+                                    directional::FORBIDDEN,
+                                );
+                            }
                         }
+                        self.last_drawn_cell = Some(cell_pos);
+                    } else {
+                        self.last_drawn_cell = None;
                     }
                     if mouse_triggered_or_pressed.contains(&MouseButton::Right) {
                         for cell_pos in &big_cell_pos {
-                            self.map.cost.set(&cell_pos, 1);
+                            self.map.clear_directional(layer, cell_pos);
                         }
                     }
-                    if mouse_triggered.contains(&MouseButton::Middle) {
-                        self.map.reset();
-                    }
                 }
                 ui_impl::CursorControl::TripSetting => {
                     if mouse_triggered.contains(&MouseButton::Left) {
@@ -195,23 +429,51 @@ impl EventHandler for MainState {
                     }
                     if mouse_triggered.contains(&MouseButton::Right) {
                         if self.ui().auto_delete {
-                            self.path_computer.full_paths.clear();
+                            self.path_computer.clear_layer(layer);
+                            self.cancel_background(Some(layer));
                         }
 
-                        self.path_computer.begin_full_path(
-                            self.start,
-                            cell_pos,
-                            self.map.cost.clone(),
-                        );
+                        let movement = self.movement();
+                        self.trips.insert(movement, (self.start, cell_pos));
+                        self.repaths.remove(&movement);
+                        if self.ui().compute_background {
+                            let id = self.path_computer.new_id();
+                            let job = self.path_worker.submit(
+                                id,
+                                self.start,
+                                cell_pos,
+                                &self.map,
+                                layer,
+                                movement.clearance,
+                            );
+                            self.path_jobs.push((movement, job));
+                        } else {
+                            let id = self.path_computer.begin_full_path(
+                                self.start,
+                                cell_pos,
+                                &self.map,
+                                layer,
+                                movement.clearance,
+                            );
+                            self.followed_paths.insert(movement, id);
+                        }
                     }
                     if mouse_triggered.contains(&MouseButton::Middle) {
-                        self.path_computer.astars.clear();
-                        self.path_computer.full_paths.clear();
+                        self.path_computer.clear();
+                        self.cancel_background(None);
+                        self.trips.clear();
+                        self.repaths.clear();
                     }
                 }
             }
         }
 
+        if !mouse_triggered_or_pressed.contains(&MouseButton::Left)
+            && !mouse_triggered_or_pressed.contains(&MouseButton::Right)
+        {
+            self.history.end(&self.map);
+        }
+
         self.ui_mut().reset_trigger();
 
         Ok(())
@@ -374,15 +636,38 @@ fn cell_pos_2_rect(cell_pos: &CellPos) -> Rect {
 impl MainState {
     pub fn new(mut ctx: &mut Context, hidpi_factor: f32) -> GameResult<MainState> {
         let imgui_wrapper = ImGuiWrapper::new(&mut ctx);
+        let mut map = Map::new(MAP_SIZE);
+        map.add_layer("wheeled");
+        map.add_layer("hover");
+
         let mut s = MainState {
             hidpi_factor,
             imgui_wrapper,
-            map: Map::new(MAP_SIZE),
+            map,
+            history: History::new(HISTORY_SIZE),
             sprite: AllSprite::new(ctx)?,
             path_computer: PathComputer::new(),
+            path_worker: PathWorker::new(),
+            path_jobs: Vec::new(),
+            followed_paths: HashMap::new(),
+            trips: HashMap::new(),
+            repaths: HashMap::new(),
+            repath_ticks: 0,
+            seen_chunks: HashMap::new(),
+            last_drawn_cell: None,
+            shape_start: None,
+            crowd: Crowd::new(MAP_SIZE, MAP_SIZE),
             start: CellPos::new(),
             agents: Vec::new(),
+            world_computer: PathComputer::new(),
+            crossing: None,
         };
+        s.ui_mut().layer_names = s
+            .map
+            .layers
+            .iter()
+            .map(|layer| ImString::new(layer.name.clone()))
+            .collect();
 
         graphics::set_mode(
             ctx,
@@ -400,20 +685,67 @@ impl MainState {
             },
         )?;
 
-        //        for _ in 0..50 {
-        //            s.path_computer.astars.push(PathComputer::astar(
-        //                CellPos::new(),
-        //                CellPos {
-        //                    i: MAP_SIZE - 1,
-        //                    j: MAP_SIZE / 2,
-        //                },
-        //                s.map.cost.clone(),
-        //            ));
-        //        }
-
         Ok(s)
     }
 
+    fn layer(&self) -> LayerId {
+        LayerId(self.ui().layer)
+    }
+
+    // Path followed by the agents of the current layer and clearance
+    fn followed_result(&self) -> Option<&pathfinding::Result> {
+        self.followed_paths
+            .get(&self.movement())
+            .and_then(|id| self.path_computer.full_path_result(*id))
+    }
+
+    // Checked on the cost of the layer, so a path computed on a congested cost shows as
+    // suboptimal
+    fn validate_flow(&self) -> Option<Report> {
+        let movement = self.movement();
+        let result = self.followed_result()?;
+        let (_, goal) = self.trips.get(&movement)?;
+        let mut cost = self.map.cost(movement.layer);
+        if movement.clearance > 1 {
+            let clearance = clearance::clearance(&cost, self.map.topology);
+            clearance::restrict_cost(&mut cost, &clearance, movement.clearance);
+        }
+        Some(validation::validate(
+            result,
+            &cost,
+            self.map.directional(movement.layer),
+            self.map.topology,
+            goal,
+            SUBOPTIMAL_TOLERANCE,
+        ))
+    }
+
+    // An edit made at once, that can be undone on its own
+    fn record_edit(&mut self, layer: LayerId, edit: impl FnOnce(&mut Map)) {
+        self.history.begin(&self.map, layer);
+        edit(&mut self.map);
+        self.history.end(&self.map);
+    }
+
+    // Left paints the chosen cost, right erases
+    fn brush(&self, button: MouseButton) -> Brush {
+        Brush {
+            radius: self.ui().brush_radius as usize,
+            value: match button {
+                MouseButton::Left => self.ui().brush_cost as u8,
+                _ => 1,
+            },
+            softness: self.ui().brush_softness,
+        }
+    }
+
+    fn movement(&self) -> Movement {
+        Movement {
+            layer: self.layer(),
+            clearance: self.ui().unit_clearance as u8,
+        }
+    }
+
     fn ui_mut(&mut self) -> &mut HighLevelUI {
         &mut self.imgui_wrapper.ui
     }
@@ -423,74 +755,283 @@ impl MainState {
     }
 
     fn compute_all(&mut self) {
-        let mut computed_anything = false;
-        let start = std::time::Instant::now();
-        for astar in &mut self.path_computer.astars {
-            while match astar {
-                AStarCompute::Computed { .. } => false,
-                _ => true,
-            } {
-                computed_anything = true;
-                AStarCompute::step_replace(astar);
+        let usage = self.path_computer.compute_all();
+        if usage.steps > 0 {
+            self.ui_mut().last_compute_ms = usage.elapsed.as_millis()
+        }
+    }
+
+    fn compute_live(&mut self) {
+        if self.ui().use_budget {
+            let budget = Budget {
+                time: Some(Duration::from_secs_f32(self.ui().budget_ms / 1000.0)),
+                nodes: match self.ui().budget_nodes {
+                    0 => None,
+                    nodes => Some(nodes as usize),
+                },
+            };
+            let usage = self.path_computer.compute_with_budget(budget);
+            self.ui_mut().last_budget_nodes = usage.nodes;
+        } else {
+            for _ in 0..self.ui().step_per_frame {
+                self.compute_step();
             }
         }
+    }
+
+    fn compute_step(&mut self) {
+        self.path_computer.compute_step();
+    }
 
-        for full in &mut self.path_computer.full_paths {
-            while match full {
-                FullPathCompute::FlowFieldComputed { .. } => false,
-                _ => true,
-            } {
-                computed_anything = true;
-                FullPathCompute::step_replace(full);
+    // Finished background jobs are moved into the path computer, as if they were computed here.
+    // The tick never waits for the worker, pending jobs are polled again next update.
+    fn poll_background(&mut self) {
+        let jobs = std::mem::take(&mut self.path_jobs);
+        for (movement, job) in jobs {
+            let id = job.id();
+            let result = match job.poll() {
+                JobStatus::Pending => {
+                    self.path_jobs.push((movement, job));
+                    None
+                }
+                JobStatus::Done(result) => Some(*result),
+                JobStatus::Cancelled => None,
+            };
+            if let Some(result) = result {
+                self.path_computer.push_full_path(
+                    id,
+                    FullPathCompute::FlowFieldComputed(result),
+                    movement.layer,
+                );
+                self.followed_paths.insert(movement, id);
             }
         }
-        if computed_anything {
-            self.ui_mut().last_compute_ms = start.elapsed().as_millis()
+        self.ui_mut().background_jobs = self.path_jobs.len();
+    }
+
+    // Agents keep following the old path until the new one is computed
+    fn swap_repaths(&mut self) {
+        let repaths: Vec<(Movement, RequestId)> =
+            self.repaths.iter().map(|(m, id)| (*m, *id)).collect();
+        for (movement, id) in repaths {
+            match self.path_computer.is_complete(id) {
+                Some(false) => {}
+                Some(true) => {
+                    self.repaths.remove(&movement);
+                    if let Some(old) = self.followed_paths.insert(movement, id) {
+                        self.path_computer.cancel(old);
+                    }
+                }
+                None => {
+                    self.repaths.remove(&movement);
+                }
+            }
         }
     }
 
-    fn compute_live(&mut self) {
-        self.compute_step();
+    // Trips whose path covers a chunk edited since the last update are computed again. A repath
+    // still running was started on the old cost, it is replaced.
+    fn repath_edits(&mut self) {
+        for index in 0..self.map.layers.len() {
+            let layer = LayerId(index);
+            let seen = self.seen_chunks.entry(layer).or_default();
+            let first = seen.is_empty();
+            let changed = self.map.changed_chunks(layer, seen);
+            if first || changed.is_empty() || !self.ui().repath_on_edit {
+                continue;
+            }
+
+            for (movement, (from, to)) in self.trips.clone() {
+                if movement.layer != layer {
+                    continue;
+                }
+                let covered = self
+                    .followed_paths
+                    .get(&movement)
+                    .and_then(|id| self.path_computer.full_path_result(*id))
+                    .is_none_or(|result| changed.iter().any(|chunk| result.covers(chunk)));
+                if !covered {
+                    continue;
+                }
+                if let Some(old) = self.repaths.remove(&movement) {
+                    self.path_computer.cancel(old);
+                }
+                let id = self.path_computer.begin_full_path(
+                    from,
+                    to,
+                    &self.map,
+                    layer,
+                    movement.clearance,
+                );
+                self.repaths.insert(movement, id);
+            }
+        }
     }
 
-    fn compute_step(&mut self) {
-        for astar in &mut self.path_computer.astars {
-            AStarCompute::step_replace(astar);
+    // Trips are computed again on the cost congested by the crowd, from where their agents are
+    fn repath_crowds(&mut self) {
+        self.repath_ticks += 1;
+        if self.repath_ticks < self.ui().repath_interval {
+            return;
         }
-        for full in &mut self.path_computer.full_paths {
-            FullPathCompute::step_replace(full);
+        self.repath_ticks = 0;
+
+        for (movement, (from, to)) in self.trips.clone() {
+            if !self.repaths.contains_key(&movement) {
+                let from = self.trailing_cell(movement).unwrap_or(from);
+                let cost = self.crowd.congested_cost(self.map.chunks(movement.layer));
+                let id = self.path_computer.begin_full_path_with_cost(
+                    from,
+                    to,
+                    cost,
+                    &self.map,
+                    movement.layer,
+                    movement.clearance,
+                );
+                self.repaths.insert(movement, id);
+            }
         }
-        ()
     }
 
-    pub fn create_demo_cost_field() -> Field<f64> {
-        use rand::{Rng, SeedableRng};
+    // Cell of the agent farthest from the goal on the path it follows. The part of the trip
+    // already walked by every agent is not computed again.
+    fn trailing_cell(&self, movement: Movement) -> Option<CellPos> {
+        let result = self
+            .followed_paths
+            .get(&movement)
+            .and_then(|id| self.path_computer.full_path_result(*id))?;
+        self.agents
+            .iter()
+            .filter(|agent| agent.movement == movement)
+            .filter_map(|agent| Some((result.cost_to_goal(agent.pos)?, agent.grid_pos())))
+            .max_by_key(|(cost, _)| *cost)
+            .map(|(_, cell_pos)| cell_pos)
+    }
 
-        let mut rng: rand::prelude::StdRng = SeedableRng::seed_from_u64(0);
+    // From corner to corner of a streaming world, only its chunks around the search stay loaded.
+    // The path is computed within its share of each frame, or on the background worker, then
+    // an agent walks it.
+    fn cross_world(&mut self) {
+        let movement = self.movement();
+        if self.ui().cross_world {
+            self.ui_mut().cross_world = false;
+            let provider = NoiseProvider {
+                seed: self.ui().seed as u64,
+            };
+            // A world of its own, square and bounded whatever the grid of the map
+            let world = Arc::new(Mutex::new(World::new(
+                WORLD_SIZE,
+                WORLD_SIZE,
+                Topology::default(),
+                self.ui().world_capacity as usize,
+                Box::new(provider),
+            )));
+            let (from, to) = ((0, 0).into(), (WORLD_SIZE - 1, WORLD_SIZE - 1).into());
+
+            if let Some(job) = self.crossing.take().and_then(|crossing| crossing.job) {
+                job.cancel();
+            }
+            self.world_computer.clear();
+            let id = self.path_computer.new_id();
+            let job = if self.ui().compute_background {
+                Some(self.path_worker.submit_world(id, from, to, world.clone()))
+            } else {
+                let compute =
+                    FullPathCompute::across_world(from, to, world.clone(), self.ui().search);
+                self.world_computer
+                    .push_full_path(id, compute, movement.layer);
+                None
+            };
+            self.crossing = Some(Crossing {
+                world,
+                id,
+                job,
+                walker: None,
+            });
+        }
 
-        let mut field = Field::<f64>::new(0.0, MAP_SIZE, MAP_SIZE);
+        let crossing = match &mut self.crossing {
+            Some(crossing) => crossing,
+            None => return,
+        };
+        if let Some(job) = crossing.job.take() {
+            match job.poll() {
+                JobStatus::Pending => crossing.job = Some(job),
+                JobStatus::Done(result) => self.world_computer.push_full_path(
+                    crossing.id,
+                    FullPathCompute::FlowFieldComputed(*result),
+                    movement.layer,
+                ),
+                JobStatus::Cancelled => {}
+            }
+        }
+        self.world_computer.compute_with_budget(Budget {
+            time: Some(WORLD_FRAME_TIME),
+            nodes: None,
+        });
 
-        //Put random
-        for i in field.arr.iter_mut() {
-            let v: f64 = rng.gen_range(0.0, 1.0);
-            let v = if v < 0.97 { 0.0 } else { 1.0 };
-            *i = v;
+        let compute = self
+            .world_computer
+            .full_paths
+            .iter()
+            .find(|request| request.id == crossing.id)
+            .map(|request| &request.compute);
+        let mut progress = Progress::of(&crossing.world.lock().unwrap(), compute);
+        progress.id = Some(crossing.id);
+        if let Some(result) = self.world_computer.full_path_result(crossing.id) {
+            let walker = crossing.walker.get_or_insert_with(|| {
+                let start = Vector2::new(0.5, 0.5) * GRID_CELL_SIZE;
+                Agent::new(start, movement)
+            });
+            walker.follow(result);
+            walker.step();
+            progress.walker = Some((walker.grid_pos(), result.cost_to_goal(walker.pos)));
         }
+        self.ui_mut().world_progress = Some(progress);
+    }
 
-        //Smooth
-        for _ in 0..4 {
-            for i in 1..MAP_SIZE - 1 {
-                for j in 1..MAP_SIZE - 1 {
-                    let mut acc = 0.0;
-                    for di in -1..=1 {
-                        for dj in -1..=1 {
-                            let ni: usize = (di + i as i32) as usize;
-                            let nj: usize = (dj + j as i32) as usize;
-                            acc += field.arr[ni + nj * MAP_SIZE]
-                        }
-                    }
-                    field.arr[i + j * MAP_SIZE] = 1.7 * (acc / 9.0).powf(1.2);
-                }
+    // Whether the request is computed here or on the background worker
+    fn cancel(&mut self, id: RequestId) {
+        if !self.path_computer.cancel(id) {
+            for (_, job) in self.path_jobs.iter().filter(|(_, job)| job.id() == id) {
+                job.cancel();
+            }
+        }
+    }
+
+    fn set_priority(&mut self, id: RequestId, priority: i32) {
+        if !self.path_computer.set_priority(id, priority) {
+            for (_, job) in self.path_jobs.iter().filter(|(_, job)| job.id() == id) {
+                job.set_priority(priority);
+            }
+        }
+    }
+
+    // None cancels the jobs of every layer
+    fn cancel_background(&mut self, layer: Option<LayerId>) {
+        let jobs = std::mem::take(&mut self.path_jobs);
+        for (movement, job) in jobs {
+            if layer.is_none_or(|layer| layer == movement.layer) {
+                job.cancel();
+            } else {
+                self.path_jobs.push((movement, job));
+            }
+        }
+    }
+
+    // A few smooth hills, heights in cells, the same ones for a seed
+    pub fn create_demo_height_field(seed: u64) -> Field<f32> {
+        let mut rng = StdRng::seed_from_u64(seed);
+        let mut field = Field::<f32>::new(0.0, MAP_SIZE, MAP_SIZE);
+
+        for _ in 0..12 {
+            let ci: f32 = rng.gen_range(0.0, MAP_SIZE as f32);
+            let cj: f32 = rng.gen_range(0.0, MAP_SIZE as f32);
+            let radius: f32 = rng.gen_range(4.0, MAP_SIZE as f32 / 4.0);
+            let top: f32 = rng.gen_range(2.0, 12.0);
+            for (CellPos { i, j }, height) in field.cells_mut() {
+                let d2 = (i as f32 - ci).powi(2) + (j as f32 - cj).powi(2);
+                *height += top * (-d2 / (radius * radius)).exp();
             }
         }
         field
@@ -516,37 +1057,35 @@ impl MainState {
         }
 
         for astar in self.path_computer.all_astars() {
-            match astar {
-                AStarCompute::Computing {
-                    from,
-                    to,
-                    open_nodes,
-                    nodes_state,
-                    ..
-                } => {
-                    color_pixel(from, &[0.0, 1.0, 0.0, 1.0], self.map.size, &mut color_vec);
-                    color_pixel(to, &[1.0, 1.0, 0.0, 1.0], self.map.size, &mut color_vec);
-                    for node in open_nodes {
-                        color_pixel(
-                            &node.cell_pos,
-                            &[1.0, 0.0, 1.0, 1.0],
-                            self.map.size,
-                            &mut color_vec,
-                        )
-                    }
-                    //                    for node in closed_nodes {
-                    //                        color_pixel(&node, &[1.0, 0.0, 0.0, 0.2], self.map.size, &mut color_vec)
-                    //                    }
+            if let AStarCompute::Computing {
+                from,
+                to,
+                open_nodes,
+                backward,
+                ..
+            } = astar
+            {
+                color_pixel(from, &[0.0, 1.0, 0.0, 1.0], self.map.size, &mut color_vec);
+                color_pixel(to, &[1.0, 1.0, 0.0, 1.0], self.map.size, &mut color_vec);
+                for node in open_nodes {
+                    color_pixel(
+                        &node.cell_pos,
+                        &[1.0, 0.0, 1.0, 1.0],
+                        self.map.size,
+                        &mut color_vec,
+                    )
                 }
-
-                AStarCompute::Computed(astar::Result { from, to, path, .. }) => {
-                    color_pixel(from, &[0.0, 1.0, 0.0, 1.0], self.map.size, &mut color_vec);
-                    color_pixel(to, &[1.0, 1.0, 0.0, 1.0], self.map.size, &mut color_vec);
-                    for node in path {
-                        color_pixel(node, &[0.0, 0.0, 1.0, 0.5], self.map.size, &mut color_vec);
-                    }
+                for node in backward.iter().flat_map(|backward| &backward.open_nodes) {
+                    color_pixel(
+                        &node.cell_pos,
+                        &[0.0, 1.0, 1.0, 1.0],
+                        self.map.size,
+                        &mut color_vec,
+                    )
                 }
-                _ => {}
+                //                    for node in closed_nodes {
+                //                        color_pixel(&node, &[1.0, 0.0, 0.0, 0.2], self.map.size, &mut color_vec)
+                //                    }
             }
         }
 
@@ -556,93 +1095,77 @@ impl MainState {
                 half_screen: Vector2,
                 sprite: &mut AllSprite,
                 ctx: &mut Context,
-                computed: &Field<Option<Box<FlowField>>>,
+                fields: &[(Zone, &FlowField, usize)],
             ) {
-                if computed.arr.len() > 0 {
-                    let min = computed
-                        .arr
-                        .iter()
-                        .flatten()
-                        .map(|c| c.integration.arr.iter().min().unwrap().clone())
-                        .min()
-                        .unwrap();
-                    let max = computed
-                        .arr
-                        .iter()
-                        .flatten()
-                        .map(|c| {
-                            c.integration
-                                .arr
-                                .iter()
-                                .filter(|x| **x != flowfield::MAX_INTEGRATION)
-                                .max()
-                                .unwrap_or(&0)
-                                .clone()
-                        })
-                        .max()
-                        .unwrap_or(flowfield::MAX_INTEGRATION);
-
-                    for (index, c) in computed
-                        .arr
-                        .iter()
-                        .enumerate()
-                        .flat_map(|(index, c)| c.as_ref().map(|c| (index, c)))
-                    {
-                        let zone = Zone {
-                            zx: index % computed.width,
-                            zy: index / computed.width,
-                        };
-                        MainState::draw_flowfield(
-                            ui,
-                            half_screen,
-                            sprite,
-                            ctx,
-                            &zone,
-                            &c,
-                            min,
-                            max,
-                        );
-                    }
+                if fields.is_empty() {
+                    return;
                 }
-            };
+                let min = fields
+                    .iter()
+                    .map(|(_, c, _)| *c.integration.arr.iter().min().unwrap())
+                    .min()
+                    .unwrap();
+                let max = fields
+                    .iter()
+                    .map(|(_, c, _)| {
+                        *c.integration
+                            .arr
+                            .iter()
+                            .filter(|x| **x != flowfield::MAX_INTEGRATION)
+                            .max()
+                            .unwrap_or(&0)
+                    })
+                    .max()
+                    .unwrap_or(flowfield::MAX_INTEGRATION);
+
+                for field in fields {
+                    MainState::draw_flowfield(ui, half_screen, sprite, ctx, field, min, max);
+                }
+            }
+            // The sector fields, or the single one covering the whole map
+            fn fields_of<'a>(
+                computed: &'a HashMap<CellPos, Box<FlowField>>,
+                whole_map: Option<&'a FlowField>,
+            ) -> Vec<(Zone, &'a FlowField, usize)> {
+                let sectors = computed.iter().map(|(cell, c)| {
+                    let zone = Zone {
+                        zx: cell.i,
+                        zy: cell.j,
+                    };
+                    (zone, &**c, HALO)
+                });
+                let whole = whole_map.map(|c| (Zone { zx: 0, zy: 0 }, c, 0));
+                sectors.chain(whole).collect()
+            }
 
-            match full {
+            match &full.compute {
                 FullPathCompute::ComputingFlowFields {
-                    astar,
+                    path,
+                    waypoints,
                     zone_to_visit,
                     computing_zone,
                     computed,
+                    ..
                 } => {
                     let color_to_visit = [0.5, 0.1, 0.5, 0.5];
 
+                    let (size, topology) = (self.map.size, self.map.topology);
                     let mut draw_zone = |zone: &Zone, color: &[f64]| {
-                        for i in zone.min_i()..=zone.max_i().min(self.map.size - 1) {
-                            color_pixel(
-                                &(i, zone.min_j()).into(),
-                                color,
-                                self.map.size,
-                                &mut color_vec,
-                            );
-                            color_pixel(
-                                &(i, zone.max_j().min(self.map.size - 1)).into(),
-                                color,
-                                self.map.size,
-                                &mut color_vec,
-                            );
+                        // Clipped to the map, the first tile of a shifted hex row starts before it
+                        let (min_i, min_j) = zone.min(topology);
+                        let clip = |x: i32| x.max(0).min(size as i32 - 1) as usize;
+                        let (max_i, max_j) = (
+                            clip(min_i + GRID_SIZE as i32 - 1),
+                            clip(min_j + GRID_SIZE as i32 - 1),
+                        );
+                        let (min_i, min_j) = (clip(min_i), clip(min_j));
+                        for i in min_i..=max_i {
+                            color_pixel(&(i, min_j).into(), color, size, &mut color_vec);
+                            color_pixel(&(i, max_j).into(), color, size, &mut color_vec);
                         }
-                        for j in zone.min_j()..=zone.max_j().min(self.map.size - 1) {
-                            color_pixel(
-                                &(zone.min_i(), j).into(),
-                                color,
-                                self.map.size,
-                                &mut color_vec,
-                            );
-                            color_pixel(
-                                &(zone.max_i().min(self.map.size - 1), j).into(),
-                                color,
-                                self.map.size,
-                                &mut color_vec,
-                            );
+                        for j in min_j..=max_j {
+                            color_pixel(&(min_i, j).into(), color, size, &mut color_vec);
+                            color_pixel(&(max_i, j).into(), color, size, &mut color_vec);
                         }
                     };
 
@@ -652,12 +1175,24 @@ impl MainState {
 
                     draw_zone(&computing_zone, &[1.0, 0.0, 1.0, 0.5]);
 
+                    for node in path {
+                        color_pixel(node, &[1.0, 0.5, 0.0, 1.0], self.map.size, &mut color_vec);
+                    }
+                    for waypoint in waypoints.iter().flatten() {
+                        color_pixel(
+                            waypoint,
+                            &[1.0, 1.0, 1.0, 1.0],
+                            self.map.size,
+                            &mut color_vec,
+                        );
+                    }
+
                     draw_computed(
                         &self.imgui_wrapper.ui,
                         self.half_screen(),
                         &mut self.sprite,
                         ctx,
-                        computed,
+                        &fields_of(computed, None),
                     );
 
                     //                    let computing = &computed
@@ -675,19 +1210,55 @@ impl MainState {
                     //                        computing.integration.arr.iter().max().unwrap().clone(),
                     //                    );
                 }
-                FullPathCompute::FlowFieldComputed(pathfinding::Result { computed }) => {
+                FullPathCompute::WholeMap { field, .. } => {
+                    draw_computed(
+                        &self.imgui_wrapper.ui,
+                        self.half_screen(),
+                        &mut self.sprite,
+                        ctx,
+                        &fields_of(&HashMap::new(), Some(field)),
+                    );
+                }
+                FullPathCompute::FlowFieldComputed(pathfinding::Result {
+                    computed,
+                    whole_map,
+                    waypoints,
+                    ..
+                }) => {
+                    for waypoint in waypoints.iter().flatten() {
+                        color_pixel(
+                            waypoint,
+                            &[1.0, 1.0, 1.0, 1.0],
+                            self.map.size,
+                            &mut color_vec,
+                        );
+                    }
                     draw_computed(
                         &self.imgui_wrapper.ui,
                         self.half_screen(),
                         &mut self.sprite,
                         ctx,
-                        computed,
+                        &fields_of(computed, whole_map.as_deref()),
                     );
                 }
                 _ => {}
             }
         }
 
+        if let Some(report) = &self.ui().flow_report {
+            let highlights = [
+                (&report.suboptimal, [1.0, 1.0, 0.0, 0.5]),
+                (&report.dead_ends, [1.0, 0.5, 0.0, 1.0]),
+                (&report.blocked, [1.0, 0.0, 1.0, 1.0]),
+                (&report.loops, [1.0, 0.0, 0.0, 1.0]),
+            ];
+            for (cells, color) in highlights.iter() {
+                for cell_pos in cells.iter() {
+                    color_pixel(cell_pos, color, self.map.size, &mut color_vec);
+                }
+            }
+        }
+
         let mut img = ggez::graphics::Image::from_rgba8(
             ctx,
             self.map.size as u16,
@@ -727,9 +1298,25 @@ impl MainState {
 
         let mut color_vec: Vec<u8> = Vec::new();
 
+        // The cost is read from its chunks, the fields derived from it are only built when shown
+        let layer = self.layer();
+        let derived = if self.ui().show_clearance {
+            Some(self.map.clearance(layer))
+        } else if self.ui().show_congestion {
+            Some(self.crowd.congested_cost(self.map.chunks(layer)).to_field())
+        } else {
+            None
+        };
+        let chunks = self.map.chunks(layer);
+        let cost_at = |cell_pos: &CellPos| match &derived {
+            Some(field) => *field.get(cell_pos),
+            None => *chunks.get(cell_pos),
+        };
+        let size = self.map.size;
+        let cells = || (0..size).flat_map(move |j| (0..size).map(move |i| CellPos { i, j }));
         let (min, max) = (
-            self.map.cost.arr.iter().min().unwrap().clone() as i32,
-            self.map.cost.arr.iter().max().unwrap().clone() as i32,
+            cells().map(|cell_pos| cost_at(&cell_pos)).min().unwrap() as i32,
+            cells().map(|cell_pos| cost_at(&cell_pos)).max().unwrap() as i32,
         );
 
         for j in 0..self.map.size {
@@ -740,12 +1327,20 @@ impl MainState {
                     (1.0 - f64::exp(-f64::powf(i, accel))) / 0.63
                 }
 
-                let v = *self.map.cost.get(&(i, j).into()) as i32;
+                let cell_pos: CellPos = (i, j).into();
+                let v = cost_at(&cell_pos) as i32;
                 let v = (v - min) as f64 / (max - min) as f64;
 
-                color_vec.push((color_of(v, 2.0) * 255.0) as u8);
-                color_vec.push((color_of(v, 1.1) * 255.0) as u8);
-                color_vec.push((color_of(v, 0.5) * 255.0) as u8);
+                let shade = match &self.map.height {
+                    Some(height) if self.ui().shade_height => {
+                        MainState::hill_shade(height, &cell_pos)
+                    }
+                    _ => 1.0,
+                };
+
+                color_vec.push((color_of(v, 2.0) * shade * 255.0) as u8);
+                color_vec.push((color_of(v, 1.1) * shade * 255.0) as u8);
+                color_vec.push((color_of(v, 0.5) * shade * 255.0) as u8);
                 color_vec.push(255);
             }
         }
@@ -774,22 +1369,37 @@ impl MainState {
         Ok(())
     }
 
+    // Lit from the top left, flat ground is slightly darkened so slopes facing the light stand out
+    fn hill_shade(height: &Field<f32>, cell_pos: &CellPos) -> f64 {
+        let at = |i: usize, j: usize| {
+            *height.get(&CellPos {
+                i: i.min(height.width - 1),
+                j: j.min(height.height - 1),
+            }) as f64
+        };
+        let (i, j) = (cell_pos.i, cell_pos.j);
+        let di = (at(i + 1, j) - at(i.saturating_sub(1), j)) / 2.0;
+        let dj = (at(i, j + 1) - at(i, j.saturating_sub(1))) / 2.0;
+        let normal = na::Vector3::new(-di, -dj, 1.0).normalize();
+        let light = na::Vector3::new(-1.0, -1.0, 1.0).normalize();
+        (0.3 + 0.7 * normal.dot(&light)).clamp(0.2, 1.0)
+    }
+
     pub fn draw_flowfield(
         ui: &HighLevelUI,
         half_screen: Vector2,
         sprite: &mut AllSprite,
         ctx: &mut Context,
-        zone: &Zone,
-        flowfield: &FlowField,
+        // With the cells around the field that are not drawn
+        (zone, flowfield, halo): &(Zone, &FlowField, usize),
         min: i32,
         max: i32,
     ) -> GameResult<()> {
+        let halo = *halo;
+        let (min_i, min_j) = zone.min(flowfield.topology);
         let point = na::Point2::from(
             ui.cam_pos_smooth
-                + misc::Vector2::new(
-                    zone.min_i() as f32 * GRID_CELL_SIZE,
-                    zone.min_j() as f32 * GRID_CELL_SIZE,
-                ),
+                + misc::Vector2::new(min_i as f32 * GRID_CELL_SIZE, min_j as f32 * GRID_CELL_SIZE),
         );
 
         let param = graphics::DrawParam::new()
@@ -797,17 +1407,17 @@ impl MainState {
             .offset(na::Point2::new(0.0, 0.0))
             .scale(na::Vector2::new(ui.zoom_smooth, ui.zoom_smooth));
 
-        let mut color_vec: Vec<u8> = Vec::with_capacity(GRID_SIZE * GRID_SIZE * 4);
-
-        for j in 0..GRID_SIZE {
-            for i in 0..GRID_SIZE {
-                let (i, j) = (i as f32, j as f32);
+        let width = flowfield.integration.width - 2 * halo;
+        let height = flowfield.integration.height - 2 * halo;
+        let mut color_vec: Vec<u8> = Vec::with_capacity(width * height * 4);
 
+        for j in 0..height {
+            for i in 0..width {
                 fn color_of(i: f64, accel: f64) -> f64 {
                     (1.0 - f64::exp(-f64::powf(i, accel))) / 0.63
                 }
 
-                let v = { flowfield.integration.get(&(i, j).into()) };
+                let v = { flowfield.integration.get(&(i + halo, j + halo).into()) };
 
                 let v = (v - min) as f64 / (max - min) as f64;
 
@@ -823,12 +1433,8 @@ impl MainState {
             }
         }
 
-        let mut img = ggez::graphics::Image::from_rgba8(
-            ctx,
-            GRID_SIZE as u16,
-            GRID_SIZE as u16,
-            &color_vec[..],
-        )?;
+        let mut img =
+            ggez::graphics::Image::from_rgba8(ctx, width as u16, height as u16, &color_vec[..])?;
 
         img.set_filter(ggez::graphics::FilterMode::Nearest);
         graphics::draw(
@@ -844,13 +1450,23 @@ impl MainState {
                 )),
         )?;
 
-        for visit in &flowfield.to_visit {
+        let drawn = |cell: &CellPos| {
+            if cell.i < halo || cell.j < halo || cell.i >= width + halo || cell.j >= height + halo {
+                None
+            } else {
+                Some(CellPos {
+                    i: cell.i - halo,
+                    j: cell.j - halo,
+                })
+            }
+        };
+        for visit in flowfield.to_visit.iter().flat_map(drawn) {
             let color = [0.0, 0.2, 1.0, 0.5].into();
 
             let rectangle = graphics::Mesh::new_rectangle(
                 ctx,
                 graphics::DrawMode::fill(),
-                cell_pos_2_rect(visit),
+                cell_pos_2_rect(&visit),
                 color,
             )?;
             graphics::draw(ctx, &rectangle, param)?;
@@ -858,13 +1474,11 @@ impl MainState {
 
         //Flow arrow
         if ui.flowfield_show_arrow {
-            for j in 0..GRID_SIZE {
-                for i in 0..GRID_SIZE {
+            for j in 0..height {
+                for i in 0..width {
+                    let v = flowfield.flow.get(&(i + halo, j + halo).into());
                     let (i, j) = (i as f32, j as f32);
-
-                    let v = flowfield.flow.get(&(i, j).into());
-                    let x = (v % 3) - 1;
-                    let y = (v / 3) - 1;
+                    let (x, y) = flowfield.topology.flow_step(*v).unwrap_or((0, 0));
 
                     let p = graphics::DrawParam::new().dest(na::Point2::new(
                         i * GRID_CELL_SIZE + (GRID_CELL_SIZE - 3.0) / 2.0,
@@ -1,53 +1,132 @@
-use crate::field::Field;
-use crate::flowfield::GRID_SIZE;
+use crate::chunk::Chunks;
+use crate::clearance;
+use crate::directional::{self, DirectionalCost};
+use crate::field::{CellPos, Field, Topology};
+use crate::terrain;
 
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub struct LayerId(pub usize);
+
+// Every map starts with this layer
+pub const GROUND: LayerId = LayerId(0);
+
+// Each movement type sees the terrain through its own cost layer
 #[derive(Clone)]
-pub struct Map {
-    pub size: usize,
-    pub chunks_on_x: u32,
-    pub cost: Field<u8>,
+pub struct CostLayer {
+    pub name: String,
+    // Chunks line up with the flow field sectors, their versions tell which ones were edited
+    pub cost: Chunks<u8>,
+    // Only allocated once a one-way or directional cost is painted on the layer
+    pub directional: Option<DirectionalCost>,
 }
 
-struct LocalCoordinate {
-    chunk_x: u32,
-    chunk_y: u32,
-    local_x: u32,
-    local_y: u32,
+#[derive(Clone)]
+pub struct Map {
+    pub size: usize,
+    pub layers: Vec<CostLayer>,
+    pub height: Option<Field<f32>>,
+    pub topology: Topology,
 }
 
-//impl LocalCoordinate {
-//    fn get_chunk(&self, map: &Map) -> Field<u32> {
-//        map.chunks[self.chunk_x + self.chunk_y * map.chunks_on_x];
-//    }
-//}
-
 impl Map {
     pub fn new(size: usize) -> Map {
-        let chunks_on_x = (size as f32 / GRID_SIZE as f32).ceil() as u32;
-        let total_chunks = chunks_on_x * chunks_on_x;
-
         Map {
             size,
-            chunks_on_x,
-            cost: Field::new(1, size, size),
+            layers: vec![CostLayer {
+                name: String::from("ground"),
+                cost: Chunks::new(1, size, size),
+                directional: None,
+            }],
+            height: None,
+            topology: Topology::default(),
+        }
+    }
+
+    pub fn add_layer(&mut self, name: &str) -> LayerId {
+        self.layers.push(CostLayer {
+            name: String::from(name),
+            cost: Chunks::new(1, self.size, self.size),
+            directional: None,
+        });
+        LayerId(self.layers.len() - 1)
+    }
+
+    // The whole layer at once, for the searches
+    pub fn cost(&self, layer: LayerId) -> Field<u8> {
+        self.layers[layer.0].cost.to_field()
+    }
+
+    pub fn chunks(&self, layer: LayerId) -> &Chunks<u8> {
+        &self.layers[layer.0].cost
+    }
+
+    pub fn set_cost(&mut self, layer: LayerId, cell: &CellPos, cost: u8) {
+        self.layers[layer.0].cost.set(cell, cost);
+    }
+
+    pub fn set_cost_field(&mut self, layer: LayerId, cost: &Field<u8>) {
+        self.layers[layer.0].cost.write(cost);
+    }
+
+    // For the painting tools, they set the cells they touch, so only the chunks of these
+    // count as changed
+    pub fn edit_cost<R>(&mut self, layer: LayerId, edit: impl FnOnce(&mut Chunks<u8>) -> R) -> R {
+        edit(&mut self.layers[layer.0].cost)
+    }
+
+    // Chunks of the layer edited since the versions seen
+    pub fn changed_chunks(&self, layer: LayerId, seen: &mut Vec<u64>) -> Vec<CellPos> {
+        self.layers[layer.0].cost.changed_since(seen)
+    }
+
+    pub fn directional(&self, layer: LayerId) -> &Option<DirectionalCost> {
+        &self.layers[layer.0].directional
+    }
+
+    // Extra cost to leave cell toward (di, dj), directional::FORBIDDEN makes it one-way
+    pub fn set_directional(&mut self, layer: LayerId, cell: &CellPos, di: i32, dj: i32, extra: u8) {
+        let size = self.size;
+        let directional = self.layers[layer.0]
+            .directional
+            .get_or_insert_with(|| Field::new([0; 8], size, size));
+        directional.get_mut(cell)[directional::direction_index(di, dj)] = extra;
+    }
+
+    // Every direction of a cell at once, all zeros don't allocate the directional cost
+    pub fn set_directional_cell(&mut self, layer: LayerId, cell: &CellPos, extra: [u8; 8]) {
+        if extra == [0; 8] {
+            self.clear_directional(layer, cell);
+            return;
         }
+        let size = self.size;
+        self.layers[layer.0]
+            .directional
+            .get_or_insert_with(|| Field::new([0; 8], size, size))
+            .set(cell, extra);
     }
 
-    pub fn reset(&mut self) {
-        self.cost = Field::new(1, self.size, self.size);
+    pub fn clear_directional(&mut self, layer: LayerId, cell: &CellPos) {
+        if let Some(directional) = &mut self.layers[layer.0].directional {
+            directional.set(cell, [0; 8]);
+        }
     }
 
-    fn xy_to_cxcy_lxly(&self, x: u32, y: u32) -> LocalCoordinate {
-        LocalCoordinate {
-            chunk_x: x / self.chunks_on_x,
-            chunk_y: y / self.chunks_on_x,
-            local_x: x % self.chunks_on_x,
-            local_y: y % self.chunks_on_x,
+    // Replaces the directional cost of the layer by the one derived from the slope of each move
+    // over the height field
+    pub fn apply_height(&mut self, layer: LayerId, max_slope: f32) {
+        if let Some(height) = &self.height {
+            let extra = terrain::slope_cost(height, self.topology, max_slope);
+            self.layers[layer.0].directional = Some(extra);
         }
     }
 
-    //    fn get(&self, x: u32, y: u32, v: u32) {
-    //        let lc = self.xy_to_cxcy_lxly(x, y);
-    //                let chunk = self.chunks[lc.chunk_x,lc.chunk_y];
-    //    }
+    pub fn clearance(&self, layer: LayerId) -> Field<u8> {
+        clearance::clearance(&self.cost(layer), self.topology)
+    }
+
+    pub fn reset(&mut self, layer: LayerId) {
+        let size = self.size;
+        self.set_cost_field(layer, &Field::new(1, size, size));
+        self.layers[layer.0].directional = None;
+    }
 }
@@ -0,0 +1,257 @@
+use crate::field::{CellPos, Field};
+use rand::prelude::StdRng;
+use rand::{Rng, SeedableRng};
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Generator {
+    Demo,
+    Maze,
+    Caves,
+    Dungeon,
+    Noise,
+}
+
+pub const GENERATORS: [Generator; 5] = [
+    Generator::Demo,
+    Generator::Maze,
+    Generator::Caves,
+    Generator::Dungeon,
+    Generator::Noise,
+];
+
+impl Generator {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Generator::Demo => "Demo",
+            Generator::Maze => "Maze",
+            Generator::Caves => "Caves",
+            Generator::Dungeon => "Dungeon",
+            Generator::Noise => "Noise terrain",
+        }
+    }
+
+    // The same seed and size always give the same map
+    pub fn generate(&self, seed: u64, width: usize, height: usize) -> Field<u8> {
+        match self {
+            Generator::Demo => demo(seed, width, height),
+            Generator::Maze => maze(seed, width, height),
+            Generator::Caves => caves(seed, width, height),
+            Generator::Dungeon => dungeon(seed, width, height),
+            Generator::Noise => noise(seed, width, height),
+        }
+    }
+}
+
+// Sparse random walls, smoothed into blobs of growing cost
+pub fn demo(seed: u64, width: usize, height: usize) -> Field<u8> {
+    let mut rng = StdRng::seed_from_u64(seed);
+    let mut field = Field::from_fn(width, height, |_| {
+        if rng.gen_range(0.0, 1.0) < 0.97 {
+            0.0
+        } else {
+            1.0
+        }
+    });
+
+    for _ in 0..4 {
+        for i in 1..width.saturating_sub(1) {
+            for j in 1..height.saturating_sub(1) {
+                let acc: f64 = field.view(&(i - 1, j - 1).into(), 3, 3).iter().sum();
+                field.set(&(i, j).into(), 1.7 * (acc / 9.0).powf(1.2));
+            }
+        }
+    }
+    field.map(|v| (v * 255.0).clamp(1.0, 255.0) as u8)
+}
+
+// Perfect maze, a single way between any two cells. Corridors and walls are one cell wide,
+// corridors on the odd rows and columns.
+pub fn maze(seed: u64, width: usize, height: usize) -> Field<u8> {
+    let mut rng = StdRng::seed_from_u64(seed);
+    let mut field = Field::new(255, width, height);
+    let (rooms_i, rooms_j) = (width.saturating_sub(1) / 2, height.saturating_sub(1) / 2);
+    if rooms_i == 0 || rooms_j == 0 {
+        return field;
+    }
+    let room_cell = |i: usize, j: usize| CellPos {
+        i: 2 * i + 1,
+        j: 2 * j + 1,
+    };
+
+    // Depth first, with the choice of the next room left to the seed
+    let mut visited = Field::new(false, rooms_i, rooms_j);
+    let mut stack = vec![(0, 0)];
+    visited.set(&(0, 0).into(), true);
+    field.set(&room_cell(0, 0), 1);
+    while let Some(&(i, j)) = stack.last() {
+        let next: Vec<(usize, usize)> = [(-1, 0), (1, 0), (0, -1), (0, 1)]
+            .iter()
+            .map(|(di, dj)| (i as i32 + di, j as i32 + dj))
+            .filter(|&(ni, nj)| ni >= 0 && nj >= 0)
+            .map(|(ni, nj)| (ni as usize, nj as usize))
+            .filter(|&(ni, nj)| ni < rooms_i && nj < rooms_j && !visited.get(&(ni, nj).into()))
+            .collect();
+        if next.is_empty() {
+            stack.pop();
+            continue;
+        }
+        let (ni, nj) = next[rng.gen_range(0, next.len())];
+        visited.set(&(ni, nj).into(), true);
+        field.set(
+            &CellPos {
+                i: i + ni + 1,
+                j: j + nj + 1,
+            },
+            1,
+        );
+        field.set(&room_cell(ni, nj), 1);
+        stack.push((ni, nj));
+    }
+    field
+}
+
+// Random walls, then a few rounds where a cell becomes a wall when most of the cells around it
+// are walls. The outside counts as walls, so the caves are closed.
+pub fn caves(seed: u64, width: usize, height: usize) -> Field<u8> {
+    let mut rng = StdRng::seed_from_u64(seed);
+    let mut wall = Field::from_fn(width, height, |_| rng.gen_range(0.0, 1.0) < 0.45);
+
+    for _ in 0..5 {
+        wall = Field::from_fn(width, height, |CellPos { i, j }| {
+            let min = CellPos {
+                i: i.saturating_sub(1),
+                j: j.saturating_sub(1),
+            };
+            let size_i = i + 2 - min.i;
+            let size_j = j + 2 - min.j;
+            let around = wall.view(&min, size_i, size_j);
+            let inside = around.width * around.height;
+            let walls = around.iter().filter(|wall| **wall).count() + 9 - inside;
+            walls >= 5
+        });
+    }
+    wall.map(|wall| if *wall { 255 } else { 1 })
+}
+
+// Rectangular rooms that don't overlap, each joined to the previous one by an L shaped
+// corridor two cells wide
+pub fn dungeon(seed: u64, width: usize, height: usize) -> Field<u8> {
+    let mut rng = StdRng::seed_from_u64(seed);
+    let mut field = Field::new(255, width, height);
+    if width < 8 || height < 8 {
+        return field;
+    }
+
+    let mut rooms: Vec<(CellPos, CellPos)> = Vec::new();
+    for _ in 0..width * height / 400 {
+        let room_i = rng.gen_range(4, (width / 4).max(5).min(width - 2));
+        let room_j = rng.gen_range(4, (height / 4).max(5).min(height - 2));
+        let min = CellPos {
+            i: rng.gen_range(1, width - room_i),
+            j: rng.gen_range(1, height - room_j),
+        };
+        let max = CellPos {
+            i: min.i + room_i - 1,
+            j: min.j + room_j - 1,
+        };
+        // A wall is kept between two rooms
+        let overlaps = rooms.iter().any(|(other_min, other_max)| {
+            min.i <= other_max.i + 1
+                && other_min.i <= max.i + 1
+                && min.j <= other_max.j + 1
+                && other_min.j <= max.j + 1
+        });
+        if !overlaps {
+            rooms.push((min, max));
+        }
+    }
+
+    let mut carve = |from: &CellPos, to: &CellPos| {
+        for j in from.j.min(to.j)..=from.j.max(to.j) {
+            for i in from.i.min(to.i)..=from.i.max(to.i) {
+                field.set(&CellPos { i, j }, 1);
+            }
+        }
+    };
+    for (min, max) in &rooms {
+        carve(min, max);
+    }
+    let center = |(min, max): &(CellPos, CellPos)| CellPos {
+        i: (min.i + max.i) / 2,
+        j: (min.j + max.j) / 2,
+    };
+    for pair in rooms.windows(2) {
+        let (from, to) = (center(&pair[0]), center(&pair[1]));
+        let corner = CellPos { i: to.i, j: from.j };
+        carve(
+            &from,
+            &CellPos {
+                i: corner.i + 1,
+                j: corner.j + 1,
+            },
+        );
+        carve(
+            &corner,
+            &CellPos {
+                i: to.i + 1,
+                j: to.j,
+            },
+        );
+    }
+    field
+}
+
+// Fractal value noise, cut into bands from plains to impassable mountains
+pub fn noise(seed: u64, width: usize, height: usize) -> Field<u8> {
+    Field::from_fn(width, height, |CellPos { i, j }| noise_at(seed, i, j))
+}
+
+// One cell of the noise terrain, which needs no neighbors and so can be generated by chunks
+pub fn noise_at(seed: u64, i: usize, j: usize) -> u8 {
+    let octaves = 5;
+    let mut value = 0.0;
+    let mut amplitude = 0.5;
+    let mut frequency = 1.0 / 64.0;
+    for octave in 0..octaves {
+        value += amplitude
+            * value_noise(
+                seed.wrapping_add(octave),
+                i as f32 * frequency,
+                j as f32 * frequency,
+            );
+        amplitude /= 2.0;
+        frequency *= 2.0;
+    }
+    let value = value / (1.0 - 0.5f32.powi(octaves as i32));
+    match value {
+        v if v < 0.38 => 1,
+        v if v < 0.48 => 2,
+        v if v < 0.55 => 6,
+        v if v < 0.62 => 16,
+        _ => 255,
+    }
+}
+
+// Random value in [0, 1) on the corners of a unit lattice, smoothly interpolated in between
+fn value_noise(seed: u64, x: f32, y: f32) -> f32 {
+    let (x0, y0) = (x.floor(), y.floor());
+    let (fx, fy) = (x - x0, y - y0);
+    let (sx, sy) = (fx * fx * (3.0 - 2.0 * fx), fy * fy * (3.0 - 2.0 * fy));
+    let corner = |dx: i64, dy: i64| lattice(seed, x0 as i64 + dx, y0 as i64 + dy);
+    let top = corner(0, 0) + (corner(1, 0) - corner(0, 0)) * sx;
+    let bottom = corner(0, 1) + (corner(1, 1) - corner(0, 1)) * sx;
+    top + (bottom - top) * sy
+}
+
+fn lattice(seed: u64, x: i64, y: i64) -> f32 {
+    // splitmix64 finalizer
+    let mut z = seed
+        .wrapping_mul(0x9E37_79B9_7F4A_7C15)
+        .wrapping_add(x as u64)
+        .wrapping_mul(0xBF58_476D_1CE4_E5B9)
+        .wrapping_add(y as u64);
+    z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+    z ^= z >> 31;
+    (z >> 40) as f32 / (1u64 << 24) as f32
+}
@@ -0,0 +1,157 @@
+use crate::anyangle;
+use crate::chunk::Chunks;
+use crate::field::{CellPos, Topology};
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Tool {
+    Brush,
+    Line,
+    Rectangle,
+    Fill,
+}
+
+#[derive(Copy, Clone, Debug)]
+pub struct Brush {
+    // 0 paints a single cell
+    pub radius: usize,
+    pub value: u8,
+    // Part of the radius over which the brush fades out, 0 for a hard edge
+    pub softness: f32,
+}
+
+impl Brush {
+    // How much of the brush value a cell at this distance from the center takes
+    fn weight(&self, distance: f32) -> f32 {
+        let radius = self.radius as f32 + 0.5;
+        let hard = radius * (1.0 - self.softness);
+        if distance >= radius {
+            0.0
+        } else if distance <= hard {
+            1.0
+        } else {
+            (radius - distance) / (radius - hard)
+        }
+    }
+
+    // Soft edges blend the cost toward the brush value, so they build up as the brush passes
+    // again
+    pub fn stamp(&self, cost: &mut Chunks<u8>, center: &CellPos) {
+        let r = self.radius;
+        let min_i = center.i.saturating_sub(r);
+        let min_j = center.j.saturating_sub(r);
+        let max_i = (center.i + r).min(cost.width - 1);
+        let max_j = (center.j + r).min(cost.height - 1);
+        for j in min_j..=max_j {
+            for i in min_i..=max_i {
+                let di = i as f32 - center.i as f32;
+                let dj = j as f32 - center.j as f32;
+                let weight = self.weight((di * di + dj * dj).sqrt());
+                if weight > 0.0 {
+                    let cell_pos = CellPos { i, j };
+                    let cell = *cost.get(&cell_pos) as f32;
+                    let blended = cell + (self.value as f32 - cell) * weight;
+                    cost.set(&cell_pos, blended.round() as u8);
+                }
+            }
+        }
+    }
+
+    pub fn line(&self, cost: &mut Chunks<u8>, from: &CellPos, to: &CellPos) {
+        for cell_pos in anyangle::line(from, to) {
+            self.stamp(cost, &cell_pos);
+        }
+    }
+}
+
+// Filled, corners included
+pub fn rectangle(cost: &mut Chunks<u8>, corner: &CellPos, opposite: &CellPos, value: u8) {
+    for j in corner.j.min(opposite.j)..=corner.j.max(opposite.j) {
+        for i in corner.i.min(opposite.i)..=corner.i.max(opposite.i) {
+            cost.set(&CellPos { i, j }, value);
+        }
+    }
+}
+
+// Replaces the cost of every cell reachable from start through cells of the same cost
+pub fn flood_fill(cost: &mut Chunks<u8>, start: &CellPos, value: u8, topology: Topology) {
+    let replaced = *cost.get(start);
+    if replaced == value {
+        return;
+    }
+    cost.set(start, value);
+    let mut to_visit = vec![*start];
+    while let Some(visit) = to_visit.pop() {
+        for (neighbor, _) in topology.neighbors(&visit, cost.width, cost.height) {
+            if *cost.get(&neighbor) == replaced {
+                cost.set(&neighbor, value);
+                to_visit.push(neighbor);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn painted(cost: &Chunks<u8>, value: u8) -> usize {
+        cost.iter().filter(|cost| **cost == value).count()
+    }
+
+    #[test]
+    fn hard_brush_paints_a_disc() {
+        let mut cost = Chunks::new(1, 16, 16);
+        let brush = Brush {
+            radius: 2,
+            value: 9,
+            softness: 0.0,
+        };
+        brush.stamp(&mut cost, &(8, 8).into());
+        // Cells whose center is within 2.5 of the center
+        assert_eq!(painted(&cost, 9), 21);
+        assert_eq!(*cost.get(&(10, 8).into()), 9);
+        assert_eq!(*cost.get(&(10, 10).into()), 1);
+
+        // Clipped by the edges of the map
+        brush.stamp(&mut cost, &(0, 0).into());
+        assert_eq!(*cost.get(&(0, 0).into()), 9);
+    }
+
+    #[test]
+    fn soft_brush_fades_out() {
+        let mut cost = Chunks::new(0, 16, 16);
+        let brush = Brush {
+            radius: 4,
+            value: 200,
+            softness: 1.0,
+        };
+        brush.stamp(&mut cost, &(8, 8).into());
+        let (center, near, far) = (
+            *cost.get(&(8, 8).into()),
+            *cost.get(&(10, 8).into()),
+            *cost.get(&(12, 8).into()),
+        );
+        assert!(
+            center > near && near > far && far > 0,
+            "{} {} {}",
+            center,
+            near,
+            far
+        );
+    }
+
+    #[test]
+    fn rectangle_and_fill() {
+        let mut cost = Chunks::new(1, 16, 16);
+        rectangle(&mut cost, &(9, 3).into(), &(5, 6).into(), 255);
+        assert_eq!(painted(&cost, 255), 5 * 4);
+
+        // The walls of a ring keep the fill out of its inside
+        let mut cost = Chunks::new(1, 16, 16);
+        rectangle(&mut cost, &(4, 4).into(), &(10, 10).into(), 255);
+        rectangle(&mut cost, &(5, 5).into(), &(9, 9).into(), 1);
+        flood_fill(&mut cost, &(0, 0).into(), 7, Topology::default());
+        assert_eq!(painted(&cost, 7), 16 * 16 - 7 * 7);
+        assert_eq!(*cost.get(&(7, 7).into()), 1);
+    }
+}
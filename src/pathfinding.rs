@@ -1,38 +1,111 @@
 use crate::astar;
-use crate::astar::AStarCompute;
-use crate::field::{CellPos, Field};
-use crate::flowfield::{FlowField, FlowFieldState};
-use crate::flowfield::{GRID_SIZE, GRID_SIZE_MINUS};
-use std::cmp::Ordering;
-use std::cmp::Ordering::Greater;
+use crate::astar::{AStarCompute, Search};
+use crate::chunk::{self, Chunks};
+use crate::clearance;
+use crate::field::{CellPos, Field, Grid, Topology};
+use crate::flowfield::{FlowField, FlowFieldState, MAX_INTEGRATION};
+use crate::flowfield::{GRID_SIZE, HALO, SECTOR_FIELD_SIZE};
+use crate::map::{LayerId, Map};
+use crate::misc::Vector2;
+use crate::source::CostSource;
+use crate::world::World;
+use crate::GRID_CELL_SIZE;
 use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::fmt::Debug;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct RequestId(u64);
+
+impl fmt::Display for RequestId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+// Higher priority requests are computed first when stepping with a budget
+pub struct Request<T> {
+    pub id: RequestId,
+    pub priority: i32,
+    pub layer: LayerId,
+    pub compute: T,
+}
 
 pub struct PathComputer {
-    pub astars: Vec<AStarCompute>,
-    pub full_paths: Vec<FullPathCompute>,
+    pub full_paths: Vec<Request<FullPathCompute>>,
+    // Used by the requests begun from now on
+    pub search: Search,
+    pub mode: Mode,
+    next_id: u64,
+    turn: usize,
 }
 
 impl PathComputer {
     pub fn new() -> Self {
         PathComputer {
-            astars: Vec::new(),
             full_paths: Vec::new(),
+            search: Search::AStar,
+            mode: Mode::Hierarchical,
+            next_id: 0,
+            turn: 0,
         }
     }
 }
 
+// No limit set means compute until every request is done
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Budget {
+    pub time: Option<Duration>,
+    pub nodes: Option<usize>,
+}
+
+impl Budget {
+    pub fn is_exhausted(&self, usage: &BudgetUsage) -> bool {
+        self.time.is_some_and(|time| usage.elapsed >= time)
+            || self.nodes.is_some_and(|nodes| usage.nodes >= nodes)
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default)]
+pub struct BudgetUsage {
+    pub steps: usize,
+    pub nodes: usize,
+    pub elapsed: Duration,
+}
+
 #[derive(PartialEq, Debug, Clone)]
 pub struct Zone {
     pub zx: usize,
     pub zy: usize,
 }
 
+// Sectors are tiles of GRID_SIZE cells that don't overlap. Their fields also hold a halo of HALO
+// cells around the tile, copied from the neighboring sectors.
+// On a hex map the odd rows of tiles are shifted by half a tile, like bricks, so that a sector
+// shares a side with two others in its row and two in each row around it, six like a hex. The
+// first tile of these rows starts before the map, and also holds the end of the row when the
+// map wraps.
 impl Zone {
-    pub fn from(x: f32, y: f32) -> Zone {
+    // Sector of a cell, on a map of the given width
+    pub fn of(cell_pos: &CellPos, topology: Topology, width: usize) -> Zone {
+        let zy = cell_pos.j / GRID_SIZE;
+        let zx = (cell_pos.i + Zone::shift(zy, topology)) / GRID_SIZE;
         Zone {
-            zx: x as usize / GRID_SIZE_MINUS,
+            zx: if topology.wrap {
+                zx % FullPathCompute::zone_count(width)
+            } else {
+                zx
+            },
+            zy,
+        }
+    }
 
-            zy: y as usize / GRID_SIZE_MINUS,
+    fn shift(zy: usize, topology: Topology) -> usize {
+        match topology.grid {
+            Grid::Hex if zy % 2 == 1 => GRID_SIZE / 2,
+            _ => 0,
         }
     }
 
@@ -40,403 +113,875 @@ impl Zone {
         (self.zx, self.zy).into()
     }
 
-    pub fn min_i(&self) -> usize {
-        self.zx * GRID_SIZE_MINUS
+    // First cell of the tile
+    pub fn min(&self, topology: Topology) -> (i32, i32) {
+        (
+            (self.zx * GRID_SIZE) as i32 - Zone::shift(self.zy, topology) as i32,
+            (self.zy * GRID_SIZE) as i32,
+        )
+    }
+
+    // Cell of the sector fields for a cell of the map inside the tile
+    pub fn local(&self, cell_pos: &CellPos, topology: Topology, width: usize) -> CellPos {
+        let (min_i, min_j) = self.min(topology);
+        let i = cell_pos.i as i32 - min_i;
+        // The end of a shifted row of a wrapped map
+        let i = if i >= GRID_SIZE as i32 {
+            i - width as i32
+        } else {
+            i
+        };
+        CellPos {
+            i: i as usize + HALO,
+            j: cell_pos.j - min_j as usize + HALO,
+        }
     }
-    pub fn min_j(&self) -> usize {
-        self.zy * GRID_SIZE_MINUS
+
+    // Cell of the map for a cell of the sector fields, halo included. None outside of the map,
+    // unless it wraps.
+    pub fn global(
+        &self,
+        local: &CellPos,
+        width: usize,
+        height: usize,
+        topology: Topology,
+    ) -> Option<CellPos> {
+        let (min_i, min_j) = self.min(topology);
+        let i = min_i + local.i as i32 - HALO as i32;
+        let j = min_j + local.j as i32 - HALO as i32;
+        let (w, h) = (width as i32, height as i32);
+        if topology.wrap {
+            Some((i.rem_euclid(w), j.rem_euclid(h)).into())
+        } else if i >= 0 && j >= 0 && i < w && j < h {
+            Some((i, j).into())
+        } else {
+            None
+        }
     }
-    pub fn max_i(&self) -> usize {
-        (self.zx + 1) * GRID_SIZE_MINUS
+
+    pub fn is_halo(local: &CellPos) -> bool {
+        let tile = HALO..HALO + GRID_SIZE;
+        !tile.contains(&local.i) || !tile.contains(&local.j)
     }
-    pub fn max_j(&self) -> usize {
-        (self.zy + 1) * GRID_SIZE_MINUS
+
+    // Zones holding the halo of the tile, only the ones along its sides or those at its corners
+    // too
+    pub fn neighbors(
+        &self,
+        corners: bool,
+        width: usize,
+        height: usize,
+        topology: Topology,
+    ) -> Vec<Zone> {
+        let tile = HALO..HALO + GRID_SIZE;
+        let mut neighbors: Vec<Zone> = Vec::new();
+        for j in 0..SECTOR_FIELD_SIZE {
+            for i in 0..SECTOR_FIELD_SIZE {
+                let local = CellPos { i, j };
+                let side = tile.contains(&i) || tile.contains(&j);
+                if !Zone::is_halo(&local) || !(side || corners) {
+                    continue;
+                }
+                let zone = match self.global(&local, width, height, topology) {
+                    Some(global) => Zone::of(&global, topology, width),
+                    None => continue,
+                };
+                if zone != *self && !neighbors.contains(&zone) {
+                    neighbors.push(zone);
+                }
+            }
+        }
+        neighbors
     }
 }
 
+// Work done by one phase of a full path
+#[derive(Clone, Copy, Debug, Default)]
+pub struct PhaseStats {
+    pub steps: usize,
+    pub nodes: usize,
+    pub elapsed: Duration,
+}
+
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Stats {
+    // Over the mean cost of the chunks of a streamed cost, before the A* on its cells
+    pub coarse: astar::Stats,
+    pub astar: astar::Stats,
+    // Choosing the sectors along the A* path
+    pub sector_finding: PhaseStats,
+    pub flow_fields: PhaseStats,
+    pub sectors: usize,
+    // Cost to the goal from the start, read from the flow fields, to compare the modes
+    pub flow_cost: Option<i32>,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Mode {
+    // A* first, then flow fields on the sectors along its path only
+    Hierarchical,
+    // A single flow field over the whole map, no corridor can miss a cheaper way
+    WholeMap,
+}
+
 pub struct Result {
-    pub computed: Field<Option<Box<FlowField>>>,
+    // By zone, only the sectors of the corridor are kept
+    pub computed: HashMap<CellPos, Box<FlowField>>,
+    // Set instead of the sectors in Mode::WholeMap
+    pub whole_map: Option<Box<FlowField>>,
+    pub stats: Stats,
+    // Of the map
+    pub width: usize,
+    pub height: usize,
+    pub topology: Topology,
+    // The turns of a Theta* path, for the units that follow it rather than the flow
+    pub waypoints: Option<Vec<CellPos>>,
+}
+
+impl Result {
+    // Sector of a cell and the cell in its fields
+    fn locate(&self, cell_pos: &CellPos) -> Option<(&FlowField, CellPos)> {
+        if let Some(whole_map) = &self.whole_map {
+            return Some((whole_map, *cell_pos));
+        }
+        let zone = Zone::of(cell_pos, self.topology, self.width);
+        self.computed.get(&zone.large_cell_pos()).map(|flowfield| {
+            (
+                &**flowfield,
+                zone.local(cell_pos, self.topology, self.width),
+            )
+        })
+    }
+
+    // Whether the flow depends on the cost of a chunk. The sectors line up with the chunks but on
+    // the shifted rows of a hex map, where a chunk is over two of them.
+    pub fn covers(&self, chunk: &CellPos) -> bool {
+        let first = chunk.i * GRID_SIZE;
+        let last = (first + GRID_SIZE).min(self.width) - 1;
+        self.whole_map.is_some()
+            || [first, last].iter().any(|i| {
+                let cell_pos = (*i, chunk.j * GRID_SIZE).into();
+                let zone = Zone::of(&cell_pos, self.topology, self.width);
+                self.computed.contains_key(&zone.large_cell_pos())
+            })
+    }
+
+    // Cost to the goal, None out of the sectors computed or where the goal can't be reached
+    pub fn integration_at(&self, cell_pos: &CellPos) -> Option<i32> {
+        self.locate(cell_pos)
+            .map(|(flowfield, local)| *flowfield.integration.get(&local))
+            .filter(|integration| *integration < MAX_INTEGRATION)
+    }
+
+    // Direction code of the flow, see Topology::flow_code
+    pub fn flow_at(&self, cell_pos: &CellPos) -> Option<i8> {
+        self.locate(cell_pos)
+            .map(|(flowfield, local)| *flowfield.flow.get(&local))
+    }
+
+    fn cell_at(&self, pos: Vector2) -> Option<CellPos> {
+        let (i, j) = (pos.x / GRID_CELL_SIZE, pos.y / GRID_CELL_SIZE);
+        if i < 0.0 || j < 0.0 || i >= self.width as f32 || j >= self.height as f32 {
+            None
+        } else {
+            Some((i, j).into())
+        }
+    }
+
+    // Same as integration_at, for a position in pixels
+    pub fn cost_to_goal(&self, pos: Vector2) -> Option<i32> {
+        self.cell_at(pos)
+            .and_then(|cell_pos| self.integration_at(&cell_pos))
+    }
+
+    // Unit step toward the goal on each axis, for a position in pixels
+    pub fn direction(&self, pos: Vector2) -> Option<Vector2> {
+        self.cell_at(pos)
+            .and_then(|cell_pos| self.flow_at(&cell_pos))
+            .and_then(|flow| self.topology.flow_step(flow))
+            .map(|(di, dj)| Vector2::new(di as f32, dj as f32))
+    }
+
+    // Whole map views, MAX_INTEGRATION and no direction out of the sectors computed
+    pub fn integration(&self) -> Field<i32> {
+        Field::from_fn(self.width, self.height, |cell_pos| {
+            self.integration_at(&cell_pos).unwrap_or(MAX_INTEGRATION)
+        })
+    }
+
+    pub fn flow(&self) -> Field<i8> {
+        Field::from_fn(self.width, self.height, |cell_pos| {
+            self.flow_at(&cell_pos).unwrap_or(self.topology.no_flow())
+        })
+    }
 }
 
 pub enum FullPathCompute {
+    // For a streamed cost, the A* over the mean cost of the chunks first. The A* on the cells
+    // then only searches the chunks around its path.
+    Coarse {
+        coarse: AStarCompute,
+        fine: AStarCompute,
+    },
     Astar(AStarCompute),
     AstarDoneFieldFinding {
         astar: astar::Result,
+        stats: Stats,
     },
+    // Nothing here needs the cost of the map, the sectors hold their own
     ComputingFlowFields {
-        astar: astar::Result,
+        from: CellPos,
+        path: Vec<CellPos>,
+        // See Result::waypoints
+        waypoints: Option<Vec<CellPos>>,
+        // Of the map
+        width: usize,
+        height: usize,
+        topology: Topology,
         zone_to_visit: Vec<Zone>,
         computing_zone: Zone,
-        computed: Field<Option<Box<FlowField>>>,
+        computed: HashMap<CellPos, Box<FlowField>>,
+        stats: Stats,
+    },
+    WholeMap {
+        from: CellPos,
+        field: Box<FlowField>,
+        stats: Stats,
     },
     FlowFieldComputed(Result),
 }
 
 impl FullPathCompute {
-    fn zone_global_cost_to_local_cost(zone: &Zone, global_cost: &Field<u8>) -> Field<u8> {
-        let mut computing_field: Field<u8> = Field::new(255, GRID_SIZE, GRID_SIZE);
-        for i in zone.min_i()..=zone.max_i().min(global_cost.width - 1) {
-            for j in zone.min_j()..=zone.max_j().min(global_cost.height - 1) {
-                let cell_pos = CellPos {
-                    i: i - zone.min_i(),
-                    j: j - zone.min_j(),
-                };
-                computing_field.set(&cell_pos, *global_cost.get(&CellPos { i, j }))
+    // Fields of a sector from its cost and the halo around it, see CostSource::sector. Nothing to
+    // integrate until its halo is lowered. The halo cells outside of the map or of the corridor
+    // are walls, so no flow leaves the sectors computed.
+    pub fn sector(
+        zone: &Zone,
+        padded: &Field<Option<u8>>,
+        directional: Option<Field<Option<[u8; 8]>>>,
+        topology: Topology,
+        width: usize,
+        height: usize,
+        corridor: &HashSet<CellPos>,
+    ) -> Box<FlowField> {
+        let in_corridor = |local: &CellPos| {
+            !Zone::is_halo(local)
+                || zone
+                    .global(local, width, height, topology)
+                    .is_some_and(|global| {
+                        corridor.contains(&Zone::of(&global, topology, width).large_cell_pos())
+                    })
+        };
+        let cost = Field::from_fn(SECTOR_FIELD_SIZE, SECTOR_FIELD_SIZE, |local| {
+            match padded.get(&local) {
+                Some(cost) if in_corridor(&local) => *cost,
+                _ => 255,
             }
+        });
+        let directional =
+            directional.map(|directional| directional.map(|extra| extra.unwrap_or([0; 8])));
+        // Sectors never wrap, their halos do
+        let topology = Topology {
+            wrap: false,
+            ..topology
+        };
+        let mut sector = FlowField::with_cost(CellPos::new(), cost, directional, topology);
+        sector.state = FlowFieldState::Ready;
+        sector.skip_flow = true;
+        Box::new(sector)
+    }
+
+    // Zone next to the given one, through the edges of a wrapped map of the given size in cells
+    pub fn neighbor_zone(
+        zone: &Zone,
+        di: i32,
+        dj: i32,
+        width: usize,
+        height: usize,
+        topology: Topology,
+    ) -> Option<Zone> {
+        let (zx, zy) = (zone.zx as i32 + di, zone.zy as i32 + dj);
+        let w = FullPathCompute::zone_count(width) as i32;
+        let h = FullPathCompute::zone_count(height) as i32;
+        if topology.wrap {
+            Some(Zone {
+                zx: zx.rem_euclid(w) as usize,
+                zy: zy.rem_euclid(h) as usize,
+            })
+        } else if zx >= 0 && zy >= 0 && zx < w && zy < h {
+            Some(Zone {
+                zx: zx as usize,
+                zy: zy as usize,
+            })
+        } else {
+            None
         }
-        computing_field
-    }
-
-    fn compute_junction(
-        computed: &Field<Option<Box<FlowField>>>,
-        next_zone: &Zone,
-        next: &mut FlowField,
-    ) {
-        for di in -1..=1_i32 {
-            for dj in -1..=1_i32 {
-                let zx = next_zone.zx as i32 + di;
-                let zy = next_zone.zy as i32 + dj;
-
-                if (di != 0 || dj != 0)
-                    && zx >= 0
-                    && zy >= 0
-                    && zx < computed.width as i32
-                    && zy < computed.height as i32
-                {
-                    let last_zone = Zone {
-                        zx: zx as usize,
-                        zy: zy as usize,
-                    };
+    }
 
-                    if let Some(last_flowfield) = computed.get(&last_zone.large_cell_pos()) {
-                        let mut common_globals: Vec<CellPos> = Vec::new();
-                        // last | next
-                        if last_zone.zx < next_zone.zx && last_zone.zy == next_zone.zy {
-                            for j in last_zone.min_j()..=last_zone.max_j() {
-                                common_globals.push((last_zone.max_i(), j).into())
-                            }
-                        }
-                        //  next | last
-                        else if last_zone.zx > next_zone.zx && last_zone.zy == next_zone.zy {
-                            for j in last_zone.min_j()..=last_zone.max_j() {
-                                common_globals.push((last_zone.min_i(), j).into())
-                            }
-                        }
-                        //  last
-                        //  ____
-                        //  next
-                        else if last_zone.zx == next_zone.zx && last_zone.zy < next_zone.zy {
-                            for i in last_zone.min_i()..=last_zone.max_i() {
-                                common_globals.push((i, last_zone.max_j()).into())
-                            }
-                        }
-                        //  next
-                        //  ____
-                        //  last
-                        else if last_zone.zx == next_zone.zx && last_zone.zy > next_zone.zy {
-                            for i in last_zone.min_i()..=last_zone.max_i() {
-                                common_globals.push((i, last_zone.min_j()).into())
-                            }
-                        }
-                        //  next
-                        //  ____
-                        //       last
-                        else if last_zone.zx > next_zone.zx && last_zone.zy > next_zone.zy {
-                            common_globals.push((last_zone.min_i(), last_zone.min_j()).into())
-                        }
-                        //  last
-                        //  ____
-                        //       next
-                        else if last_zone.zx < next_zone.zx && last_zone.zy < next_zone.zy {
-                            common_globals.push((last_zone.max_i(), last_zone.max_j()).into())
-                        }
-                        //      next
-                        //  ____
-                        //  last
-                        else if last_zone.zx < next_zone.zx && last_zone.zy > next_zone.zy {
-                            common_globals.push((last_zone.max_i(), last_zone.min_j()).into())
-                        }
-                        //      last
-                        //  ____
-                        //  next
-                        else if last_zone.zx > next_zone.zx && last_zone.zy < next_zone.zy {
-                            common_globals.push((last_zone.min_i(), last_zone.max_j()).into())
-                        } else {
-                            println!("{:?} {:?}", last_zone, next_zone);
-                        }
+    // A wrapped map has no partial zone on its far edges, its size is a multiple of GRID_SIZE
+    fn zone_count(size: usize) -> usize {
+        size.div_ceil(GRID_SIZE)
+    }
 
-                        for global in common_globals {
-                            let local_last = CellPos {
-                                i: global.i - last_zone.min_i(),
-                                j: global.j - last_zone.min_j(),
-                            };
+    // The halo cells take the integration of the sectors they are in when it is lower, and are
+    // integrated again from there. Returns whether any was lowered.
+    fn lower_halo(
+        computed: &HashMap<CellPos, Box<FlowField>>,
+        zone: &Zone,
+        sector: &mut FlowField,
+        width: usize,
+        height: usize,
+        topology: Topology,
+    ) -> bool {
+        let mut lowered = false;
+        for local in sector.cost.positions().filter(Zone::is_halo) {
+            let global = match zone.global(&local, width, height, topology) {
+                Some(global) => global,
+                None => continue,
+            };
+            let from = Zone::of(&global, topology, width);
+            if let Some(neighbor) = computed.get(&from.large_cell_pos()) {
+                let integration = *neighbor
+                    .integration
+                    .get(&from.local(&global, topology, width));
+                if integration < *sector.integration.get(&local) {
+                    sector.integration.set(&local, integration);
+                    sector.to_visit.push(local);
+                    lowered = true;
+                }
+            }
+        }
+        if lowered {
+            sector.state = FlowFieldState::ComputingIntegration;
+        }
+        lowered
+    }
+
+    // Zones of the sectors along the path, from the start to the goal, and the ones next to them
+    pub fn corridor(
+        path: &[CellPos],
+        to: &CellPos,
+        width: usize,
+        height: usize,
+        topology: Topology,
+    ) -> HashSet<CellPos> {
+        let mut zone_traversed_vec: Vec<Zone> = Vec::new();
+        for node in path.iter().rev() {
+            let zone = Zone::of(node, topology, width);
 
-                            let local_next = CellPos {
-                                i: global.i - next_zone.min_i(),
-                                j: global.j - next_zone.min_j(),
+            match zone_traversed_vec.last() {
+                Some(last) => {
+                    if last != &zone {
+                        // Direct diagonal traversal is not allowed, the zones of a hex map all
+                        // share a side with the ones a move reaches
+                        if !last
+                            .neighbors(false, width, height, topology)
+                            .contains(&zone)
+                        {
+                            let indirection = Zone {
+                                zx: last.zx,
+                                zy: zone.zy,
                             };
+                            zone_traversed_vec.push(indirection);
+                        }
+                        zone_traversed_vec.push(zone);
+                    }
+                }
+                _ => {
+                    zone_traversed_vec.push(zone);
+                }
+            }
+        }
+
+        let mut corridor = HashSet::new();
+        corridor.insert(Zone::of(to, topology, width).large_cell_pos());
+        for zone in &zone_traversed_vec {
+            corridor.insert(zone.large_cell_pos());
+            for new_zone in zone.neighbors(false, width, height, topology) {
+                corridor.insert(new_zone.large_cell_pos());
+            }
+        }
+        corridor
+    }
+
+    // Integrates the sectors of a corridor, from the one of the goal. The path goes from the goal
+    // to the start, like the ones of the A*.
+    pub fn flow_fields(
+        path: Vec<CellPos>,
+        waypoints: Option<Vec<CellPos>>,
+        width: usize,
+        height: usize,
+        topology: Topology,
+        mut computed: HashMap<CellPos, Box<FlowField>>,
+        stats: Stats,
+    ) -> FullPathCompute {
+        let (from, to) = (*path.last().unwrap(), path[0]);
+        let goal_zone = Zone::of(&to, topology, width);
+        if let Some(goal) = computed.get_mut(&goal_zone.large_cell_pos()) {
+            goal.objective = goal_zone.local(&to, topology, width);
+            goal.state = FlowFieldState::Created;
+        }
+        FullPathCompute::ComputingFlowFields {
+            from,
+            path,
+            waypoints,
+            width,
+            height,
+            topology,
+            zone_to_visit: Vec::new(),
+            computing_zone: goal_zone,
+            computed,
+            stats,
+        }
+    }
+
+    // The whole map mode reuses the request of the A*, which it then skips. A streamed cost is
+    // never integrated whole, its path is always hierarchical.
+    pub fn begin(astar: AStarCompute, mode: Mode) -> FullPathCompute {
+        match (mode, astar) {
+            (
+                _,
+                AStarCompute::InitialData {
+                    from,
+                    to,
+                    cost:
+                        CostSource::World {
+                            world,
+                            corridor: None,
+                        },
+                    directional,
+                    topology,
+                    min_clearance,
+                    search,
+                },
+            ) => FullPathCompute::Coarse {
+                coarse: AStarCompute::InitialData {
+                    from: chunk::of(&from),
+                    to: chunk::of(&to),
+                    cost: CostSource::Coarse(world.clone()),
+                    directional: None,
+                    topology,
+                    min_clearance: 1,
+                    search: Search::AStar,
+                },
+                fine: AStarCompute::InitialData {
+                    from,
+                    to,
+                    cost: CostSource::World {
+                        world,
+                        corridor: None,
+                    },
+                    directional,
+                    topology,
+                    min_clearance,
+                    search,
+                },
+            },
+            (
+                Mode::WholeMap,
+                AStarCompute::InitialData {
+                    from,
+                    to,
+                    cost: CostSource::Chunks(cost),
+                    directional,
+                    topology,
+                    min_clearance,
+                    ..
+                },
+            ) => {
+                let mut cost = cost.to_field();
+                // Like the A* does, every free cell has a clearance of at least 1
+                if min_clearance > 1 {
+                    let clearance = clearance::clearance(&cost, topology);
+                    clearance::restrict_cost(&mut cost, &clearance, min_clearance);
+                }
+                FullPathCompute::WholeMap {
+                    from,
+                    field: Box::new(FlowField::with_cost(to, cost, directional, topology)),
+                    stats: Stats::default(),
+                }
+            }
+            (_, astar) => FullPathCompute::Astar(astar),
+        }
+    }
+
+    // From one corner of a world to the other, say, loading its chunks only along the way
+    pub fn across_world(
+        from: CellPos,
+        to: CellPos,
+        world: Arc<Mutex<World>>,
+        search: Search,
+    ) -> FullPathCompute {
+        let topology = world.lock().unwrap().topology;
+        FullPathCompute::begin(
+            AStarCompute::InitialData {
+                from,
+                to,
+                cost: CostSource::World {
+                    world,
+                    corridor: None,
+                },
+                directional: None,
+                topology,
+                min_clearance: 1,
+                search,
+            },
+            Mode::Hierarchical,
+        )
+    }
 
-                            next.integration
-                                .set(&local_next, *last_flowfield.integration.get(&local_last));
-                            next.to_visit.push(local_next);
-                            next.state = FlowFieldState::ComputingIntegration
+    // Gives the A* on the cells the chunks of the coarse path and the ones around them, whose
+    // mean cost can hide a wall
+    fn restrict(fine: AStarCompute, coarse_path: &[CellPos]) -> AStarCompute {
+        match fine {
+            AStarCompute::InitialData {
+                from,
+                to,
+                cost: CostSource::World { world, .. },
+                directional,
+                topology,
+                min_clearance,
+                search,
+            } => {
+                let (width, height) = {
+                    let world = world.lock().unwrap();
+                    (world.width, world.height)
+                };
+                let mut corridor = HashSet::new();
+                for chunk in coarse_path {
+                    let zone = Zone {
+                        zx: chunk.i,
+                        zy: chunk.j,
+                    };
+                    for di in -1..=1 {
+                        for dj in -1..=1 {
+                            let neighbor = FullPathCompute::neighbor_zone(
+                                &zone, di, dj, width, height, topology,
+                            );
+                            corridor.extend(neighbor.map(|zone| zone.large_cell_pos()));
                         }
                     }
                 }
+                AStarCompute::InitialData {
+                    from,
+                    to,
+                    cost: CostSource::World {
+                        world,
+                        corridor: Some(Arc::new(corridor)),
+                    },
+                    directional,
+                    topology,
+                    min_clearance,
+                    search,
+                }
             }
+            fine => fine,
+        }
+    }
+
+    // None while an A* runs, see AStarCompute::stats
+    pub fn stats(&self) -> Option<&Stats> {
+        match self {
+            FullPathCompute::Coarse { .. } | FullPathCompute::Astar(_) => None,
+            FullPathCompute::AstarDoneFieldFinding { stats, .. }
+            | FullPathCompute::ComputingFlowFields { stats, .. }
+            | FullPathCompute::WholeMap { stats, .. }
+            | FullPathCompute::FlowFieldComputed(Result { stats, .. }) => Some(stats),
+        }
+    }
+
+    fn stats_mut(&mut self) -> Option<&mut Stats> {
+        match self {
+            FullPathCompute::Coarse { .. } | FullPathCompute::Astar(_) => None,
+            FullPathCompute::AstarDoneFieldFinding { stats, .. }
+            | FullPathCompute::ComputingFlowFields { stats, .. }
+            | FullPathCompute::WholeMap { stats, .. }
+            | FullPathCompute::FlowFieldComputed(Result { stats, .. }) => Some(stats),
         }
     }
 
+    // The A* keeps its own statistics, the other phases are measured here
     pub fn step(self) -> Self {
+        let finding = match self {
+            FullPathCompute::Coarse { .. }
+            | FullPathCompute::Astar(_)
+            | FullPathCompute::FlowFieldComputed(_) => return self.advance(),
+            FullPathCompute::AstarDoneFieldFinding { .. } => true,
+            FullPathCompute::ComputingFlowFields { .. } | FullPathCompute::WholeMap { .. } => false,
+        };
+        let start = Instant::now();
+        let nodes = self.step_cost();
+        let mut next = self.advance();
+        if let Some(stats) = next.stats_mut() {
+            let phase = if finding {
+                &mut stats.sector_finding
+            } else {
+                &mut stats.flow_fields
+            };
+            phase.steps += 1;
+            phase.nodes += nodes;
+            phase.elapsed += start.elapsed();
+        }
+        next
+    }
+
+    fn advance(self) -> Self {
         match self {
+            FullPathCompute::Coarse { coarse, fine } if !coarse.is_computed() => {
+                let coarse = coarse.step();
+                let fine = match &coarse {
+                    AStarCompute::Computed(result) => FullPathCompute::restrict(fine, &result.path),
+                    _ => fine,
+                };
+                FullPathCompute::Coarse { coarse, fine }
+            }
+            FullPathCompute::Coarse {
+                coarse: AStarCompute::Computed(coarse),
+                fine: AStarCompute::Computed(astar),
+            } => FullPathCompute::AstarDoneFieldFinding {
+                stats: Stats {
+                    coarse: coarse.stats,
+                    astar: astar.stats,
+                    ..Stats::default()
+                },
+                astar,
+            },
+            FullPathCompute::Coarse { coarse, fine } => FullPathCompute::Coarse {
+                coarse,
+                fine: fine.step(),
+            },
             FullPathCompute::Astar(astar_compute) => {
                 let astar_compute = astar_compute.step();
                 match astar_compute {
-                    AStarCompute::Computed(astar::Result {
-                        from,
-                        to,
-                        path,
-                        cost,
-                    }) => FullPathCompute::AstarDoneFieldFinding {
-                        astar: astar::Result {
-                            from,
-                            to,
-                            path,
-                            cost,
+                    AStarCompute::Computed(astar) => FullPathCompute::AstarDoneFieldFinding {
+                        stats: Stats {
+                            astar: astar.stats,
+                            ..Stats::default()
                         },
+                        astar,
                     },
                     _ => FullPathCompute::Astar(astar_compute),
                 }
             }
 
-            FullPathCompute::AstarDoneFieldFinding { astar } => {
-                let mut zone_traversed_vec: Vec<Zone> = Vec::new();
-                for node in astar.path.iter().rev() {
-                    let zone = Zone {
-                        zx: node.i / GRID_SIZE_MINUS,
-                        zy: node.j / GRID_SIZE_MINUS,
-                    };
-
-                    match zone_traversed_vec.last() {
-                        Some(last) => {
-                            if last != &zone {
-                                // Direct diagonal traversal is not allowed
-                                if last.zx != zone.zx && last.zy != zone.zy {
-                                    let indirection = Zone {
-                                        zx: last.zx,
-                                        zy: zone.zy,
-                                    };
-                                    zone_traversed_vec.push(indirection);
-                                }
-                                zone_traversed_vec.push(zone);
-                            }
-                        }
-                        _ => {
-                            zone_traversed_vec.push(zone);
-                        }
-                    }
-                }
-
-                //                let mut with_grow = zone_traversed_vec;
-
-                let mut with_grow = Vec::new();
-                for zone in &zone_traversed_vec {
-                    for i in -1..=1_i32 {
-                        for j in -1..=1_i32 {
-                            if i == 0 || j == 0 {
-                                let zx = zone.zx as i32 + i;
-                                let zy = zone.zy as i32 + j;
-
-                                if zx >= 0
-                                    && zy >= 0
-                                    && (zx as usize) < (astar.cost.width / GRID_SIZE_MINUS + 1)
-                                    && (zy as usize) < (astar.cost.height / GRID_SIZE_MINUS + 1)
-                                {
-                                    let new_zone = Zone {
-                                        zx: zx as usize,
-                                        zy: zy as usize,
-                                    };
-                                    if !zone_traversed_vec.contains(&new_zone) {
-                                        with_grow.push(new_zone);
-                                    }
-                                }
-                            }
-                        }
-                    }
+            FullPathCompute::AstarDoneFieldFinding { astar, stats } => {
+                let (width, height) = (astar.cost.width(), astar.cost.height());
+                // No sector to integrate, and no cost to the goal
+                if astar.path.is_empty() {
+                    return FullPathCompute::FlowFieldComputed(Result {
+                        computed: HashMap::new(),
+                        whole_map: None,
+                        stats,
+                        width,
+                        height,
+                        topology: astar.topology,
+                        waypoints: None,
+                    });
                 }
-                with_grow.extend(zone_traversed_vec);
-                let mut r = with_grow.clone();
-                with_grow.reverse();
-                with_grow.extend(r);
-
-                let first_zone = with_grow.pop().unwrap();
-                let mut computing_field_cost =
-                    FullPathCompute::zone_global_cost_to_local_cost(&first_zone, &astar.cost);
-
-                let mut computing_field = Box::new(FlowField {
-                    objective: CellPos {
-                        i: astar.to.i % GRID_SIZE_MINUS,
-                        j: astar.to.j % GRID_SIZE_MINUS,
-                    },
-                    cost: computing_field_cost,
-                    integration: Field::new(
-                        crate::flowfield::MAX_INTEGRATION as i32,
-                        GRID_SIZE,
-                        GRID_SIZE,
-                    ),
-                    flow: Field::new(4, GRID_SIZE, GRID_SIZE),
-                    to_visit: Vec::new(),
-                    state: FlowFieldState::Created,
-                    skip_flow: true,
-                });
-
-                let mut computed = Field::new(
-                    None,
-                    astar.cost.width / GRID_SIZE_MINUS + 1,
-                    astar.cost.height / GRID_SIZE_MINUS + 1,
+                let corridor = FullPathCompute::corridor(
+                    &astar.path,
+                    &astar.to,
+                    width,
+                    height,
+                    astar.topology,
                 );
 
-                computed.set(&first_zone.large_cell_pos(), Some(computing_field));
+                // The cells out of the corridor are not read, a world would load them for nothing
+                let (wrap, topology) = (astar.topology.wrap, astar.topology);
+                let in_corridor = |cell_pos: &CellPos| {
+                    corridor.contains(&Zone::of(cell_pos, topology, width).large_cell_pos())
+                };
+                let computed = corridor
+                    .iter()
+                    .map(|chunk| {
+                        let zone = Zone {
+                            zx: chunk.i,
+                            zy: chunk.j,
+                        };
+                        let min = zone.min(topology);
+                        let sector = FullPathCompute::sector(
+                            &zone,
+                            &astar.cost.sector(min, HALO, wrap, in_corridor),
+                            astar.directional.as_ref().map(|directional| {
+                                chunk::sector(min, HALO, width, height, wrap, |cell_pos| {
+                                    Some(*directional.get(cell_pos))
+                                })
+                            }),
+                            astar.topology,
+                            width,
+                            height,
+                            &corridor,
+                        );
+                        (*chunk, sector)
+                    })
+                    .collect();
 
-                FullPathCompute::ComputingFlowFields {
-                    astar,
-                    zone_to_visit: with_grow,
-                    computing_zone: first_zone,
+                FullPathCompute::flow_fields(
+                    astar.path,
+                    astar.any_angle,
+                    width,
+                    height,
+                    astar.topology,
                     computed,
-                }
+                    stats,
+                )
             }
 
             FullPathCompute::ComputingFlowFields {
-                astar,
+                from,
+                path,
+                waypoints,
+                width,
+                height,
+                topology,
                 mut zone_to_visit,
-                mut computing_zone,
+                computing_zone,
                 mut computed,
+                mut stats,
             } => {
-                let mut computing = computed
-                    .get_mut(&computing_zone.large_cell_pos())
-                    .as_mut()
-                    .unwrap();
-
-                computing.step();
-
-                match computing.state {
-                    FlowFieldState::Ready => {
-                        if zone_to_visit.is_empty() {
-                            let indexes: Vec<usize> = computed
-                                .arr
-                                .iter()
-                                .enumerate()
-                                .flat_map(|(index, c)| c.as_ref().map(|c| index))
-                                .collect();
-
-                            for index in indexes {
-                                let zone = Zone {
-                                    zx: index % computed.width,
-                                    zy: index / computed.width,
-                                };
-
-                                let mut me = std::mem::replace(
-                                    computed.get_mut(&zone.large_cell_pos()),
-                                    None,
-                                );
-
-                                let mut neighborhood = HashMap::new();
-                                for i in -1..=1_i32 {
-                                    for j in -1..=1_i32 {
-                                        if (i != 0 || j != 0)
-                                            && i + zone.zx as i32 >= 0
-                                            && j + zone.zy as i32 >= 0
-                                            && (i + zone.zx as i32) < computed.width as i32
-                                            && (j + zone.zy as i32) < computed.height as i32
-                                        {
-                                            if let Some(f) = computed.get(
-                                                &(i + zone.zx as i32, j + zone.zy as i32).into(),
-                                            ) {
-                                                neighborhood.insert((i, j), f);
-                                            }
-                                        }
-                                    }
-                                }
-
-                                for me in &mut me {
-                                    me.step_flow_with_neighbors(&neighborhood);
-                                }
-
-                                std::mem::replace(computed.get_mut(&zone.large_cell_pos()), me);
-                            }
-
-                            FullPathCompute::FlowFieldComputed(Result { computed })
-                        } else {
-                            let next_zone = zone_to_visit.pop().unwrap();
-                            match computed.get(&next_zone.large_cell_pos()) {
-                                Some(next) => {
-                                    let mut next = std::mem::replace(
-                                        computed.get_mut(&next_zone.large_cell_pos()),
-                                        None,
-                                    )
-                                    .unwrap();
-
-                                    FullPathCompute::compute_junction(
-                                        &computed, &next_zone, &mut next,
-                                    );
-                                    computed.set(&next_zone.large_cell_pos(), Some(next));
-                                }
-                                None => {
-                                    let cost = FullPathCompute::zone_global_cost_to_local_cost(
-                                        &next_zone,
-                                        &astar.cost,
-                                    );
-                                    let mut next_integration = Field::new(
-                                        crate::flowfield::MAX_INTEGRATION as i32,
-                                        GRID_SIZE,
-                                        GRID_SIZE,
-                                    );
-                                    let mut next_flow = Field::<i8>::new(4, GRID_SIZE, GRID_SIZE);
-                                    let mut next_to_visit = Vec::new();
-
-                                    let mut next = Box::new(FlowField {
-                                        objective: CellPos::new(),
-                                        cost,
-                                        integration: next_integration,
-                                        flow: next_flow,
-                                        to_visit: next_to_visit,
-                                        state: FlowFieldState::ComputingIntegration,
-                                        skip_flow: true,
-                                    });
-                                    FullPathCompute::compute_junction(
-                                        &computed, &next_zone, &mut next,
-                                    );
-                                    computed.set(&next_zone.large_cell_pos(), Some(next));
-                                }
-                            }
-
-                            FullPathCompute::ComputingFlowFields {
-                                astar,
-                                zone_to_visit,
-                                computing_zone: next_zone,
-                                computed,
-                            }
-                        }
-                    }
-                    _ => FullPathCompute::ComputingFlowFields {
-                        astar,
+                let computing = computed.get_mut(&computing_zone.large_cell_pos()).unwrap();
+                if !computing.step() {
+                    return FullPathCompute::ComputingFlowFields {
+                        from,
+                        path,
+                        waypoints,
+                        width,
+                        height,
+                        topology,
                         zone_to_visit,
                         computing_zone,
                         computed,
-                    },
+                        stats,
+                    };
+                }
+
+                // Its neighbors take the integration of its border, and are integrated again
+                // when it lowers their halo, until no halo changes
+                for neighbor in computing_zone.neighbors(true, width, height, topology) {
+                    let mut sector = match computed.remove(&neighbor.large_cell_pos()) {
+                        Some(sector) => sector,
+                        None => continue,
+                    };
+                    let lowered = FullPathCompute::lower_halo(
+                        &computed,
+                        &neighbor,
+                        &mut sector,
+                        width,
+                        height,
+                        topology,
+                    );
+                    computed.insert(neighbor.large_cell_pos(), sector);
+                    if lowered && !zone_to_visit.contains(&neighbor) {
+                        zone_to_visit.push(neighbor);
+                    }
+                }
+
+                // The sector with the lowest integration to spread goes first, like the cells of
+                // a Dijkstra, so that fewer are integrated again
+                let lowest = zone_to_visit
+                    .iter()
+                    .enumerate()
+                    .min_by_key(|(_, zone)| {
+                        let sector = &computed[&zone.large_cell_pos()];
+                        sector
+                            .to_visit
+                            .iter()
+                            .map(|cell| *sector.integration.get(cell))
+                            .min()
+                    })
+                    .map(|(index, _)| index);
+                if let Some(index) = lowest {
+                    let next_zone = zone_to_visit.swap_remove(index);
+                    return FullPathCompute::ComputingFlowFields {
+                        from,
+                        path,
+                        waypoints,
+                        width,
+                        height,
+                        topology,
+                        zone_to_visit,
+                        computing_zone: next_zone,
+                        computed,
+                        stats,
+                    };
+                }
+
+                // Every halo now holds the integration of its neighbors
+                for sector in computed.values_mut() {
+                    sector.step_flow();
+                }
+                stats.sectors = computed.len();
+
+                let mut result = Result {
+                    computed,
+                    whole_map: None,
+                    stats,
+                    width,
+                    height,
+                    topology,
+                    waypoints,
+                };
+                result.stats.flow_cost = result.integration_at(&from);
+                FullPathCompute::FlowFieldComputed(result)
+            }
+            FullPathCompute::WholeMap {
+                from,
+                mut field,
+                stats,
+            } => {
+                if !field.step() {
+                    return FullPathCompute::WholeMap { from, field, stats };
                 }
+                let (width, height, topology) =
+                    (field.cost.width, field.cost.height, field.topology);
+                let mut result = Result {
+                    computed: HashMap::new(),
+                    whole_map: Some(field),
+                    stats,
+                    width,
+                    height,
+                    topology,
+                    waypoints: None,
+                };
+                result.stats.flow_cost = result.integration_at(&from);
+                FullPathCompute::FlowFieldComputed(result)
             }
             _ => self,
         }
     }
 
+    pub fn is_computed(&self) -> bool {
+        matches!(self, FullPathCompute::FlowFieldComputed(_))
+    }
+
+    // Number of nodes expanded by the next call to step
+    pub fn step_cost(&self) -> usize {
+        match self {
+            FullPathCompute::Astar(astar_compute) => astar_compute.step_cost(),
+            FullPathCompute::ComputingFlowFields {
+                computing_zone,
+                computed,
+                ..
+            } => computed
+                .get(&computing_zone.large_cell_pos())
+                .map_or(1, |computing| computing.to_visit.len().max(1)),
+            FullPathCompute::WholeMap { field, .. } => field.to_visit.len().max(1),
+            FullPathCompute::FlowFieldComputed(_) => 0,
+            _ => 1,
+        }
+    }
+
     pub fn step_replace(s: &mut FullPathCompute) {
         let old = std::mem::replace(
             s,
             FullPathCompute::Astar(AStarCompute::InitialData {
                 from: CellPos::new(),
                 to: CellPos::new(),
-                cost: Field::new(0, 0, 0),
+                cost: CostSource::Chunks(Chunks::new(0, 0, 0)),
+                directional: None,
+                topology: Topology::default(),
+                min_clearance: 0,
+                search: Search::AStar,
             }),
         );
         *s = old.step();
@@ -444,28 +989,479 @@ impl FullPathCompute {
 }
 
 impl PathComputer {
-    pub fn begin_astar(&mut self, from: CellPos, to: CellPos, cost: Field<u8>) {
-        self.astars
-            .push(AStarCompute::InitialData { from, to, cost });
+    // Also given to the requests computed elsewhere, like on the background worker, so they
+    // can be tracked from the moment they are submitted
+    pub fn new_id(&mut self) -> RequestId {
+        let id = RequestId(self.next_id);
+        self.next_id += 1;
+        id
+    }
+
+    // The sector flow fields are built from the cost restricted by the A*, so they only use
+    // cells wide enough too
+    pub fn begin_full_path(
+        &mut self,
+        from: CellPos,
+        to: CellPos,
+        map: &Map,
+        layer: LayerId,
+        min_clearance: u8,
+    ) -> RequestId {
+        let cost = map.chunks(layer).clone();
+        self.begin_full_path_with_cost(from, to, cost, map, layer, min_clearance)
+    }
+
+    // For a cost derived from a layer, like the congested cost of a crowd
+    pub fn begin_full_path_with_cost(
+        &mut self,
+        from: CellPos,
+        to: CellPos,
+        cost: Chunks<u8>,
+        map: &Map,
+        layer: LayerId,
+        min_clearance: u8,
+    ) -> RequestId {
+        let id = self.new_id();
+        self.push_full_path(
+            id,
+            FullPathCompute::begin(
+                AStarCompute::InitialData {
+                    from,
+                    to,
+                    cost: CostSource::Chunks(cost),
+                    directional: map.directional(layer).clone(),
+                    topology: map.topology,
+                    min_clearance,
+                    search: self.search,
+                },
+                self.mode,
+            ),
+            layer,
+        );
+        id
+    }
+
+    // Also used to hand over a path computed elsewhere, like on the background worker, under
+    // the id it was submitted with
+    pub fn push_full_path(&mut self, id: RequestId, compute: FullPathCompute, layer: LayerId) {
+        self.full_paths.push(Request {
+            id,
+            priority: 0,
+            layer,
+            compute,
+        });
+    }
+
+    pub fn set_priority(&mut self, id: RequestId, priority: i32) -> bool {
+        match self.full_paths.iter_mut().find(|r| r.id == id) {
+            Some(request) => {
+                request.priority = priority;
+                true
+            }
+            None => false,
+        }
+    }
+
+    // Returns false if no request has this id
+    pub fn cancel(&mut self, id: RequestId) -> bool {
+        let len = self.full_paths.len();
+        self.full_paths.retain(|r| r.id != id);
+        self.full_paths.len() != len
+    }
+
+    pub fn clear(&mut self) {
+        self.full_paths.clear();
     }
 
-    pub fn begin_full_path(&mut self, from: CellPos, to: CellPos, cost: Field<u8>) {
+    pub fn clear_layer(&mut self, layer: LayerId) {
+        self.full_paths.retain(|r| r.layer != layer);
+    }
+
+    // None if no request has this id
+    pub fn is_complete(&self, id: RequestId) -> Option<bool> {
         self.full_paths
-            .push(FullPathCompute::Astar(AStarCompute::InitialData {
-                from,
-                to,
-                cost,
-            }));
+            .iter()
+            .find(|r| r.id == id)
+            .map(|r| r.compute.is_computed())
     }
 
+    pub fn full_path_result(&self, id: RequestId) -> Option<&Result> {
+        self.full_paths
+            .iter()
+            .find(|r| r.id == id)
+            .and_then(|r| match &r.compute {
+                FullPathCompute::FlowFieldComputed(result) => Some(result),
+                _ => None,
+            })
+    }
+
+    // The A* of the full paths still searching
     pub fn all_astars(&self) -> impl Iterator<Item = &AStarCompute> {
-        let inner_astar = self.full_paths.iter().filter_map(|e| match e {
+        self.full_paths.iter().filter_map(|e| match &e.compute {
             FullPathCompute::Astar(a) => Some(a),
             _ => None,
+        })
+    }
+
+    pub fn compute_step(&mut self) {
+        for full in &mut self.full_paths {
+            FullPathCompute::step_replace(&mut full.compute);
+        }
+    }
+
+    pub fn compute_all(&mut self) -> BudgetUsage {
+        self.compute_with_budget(Budget::default())
+    }
+
+    // Steps pending requests until the budget runs out. Among the pending requests of highest
+    // priority, the one that used the least of the budget so far always goes next, so a slow
+    // request cannot starve the others.
+    pub fn compute_with_budget(&mut self, budget: Budget) -> BudgetUsage {
+        let start = Instant::now();
+        let mut usage = BudgetUsage::default();
+
+        let mut pending = self.pending();
+        if !pending.is_empty() {
+            // Ties go to a different request each call
+            let len = pending.len();
+            pending.rotate_left(self.turn % len);
+        }
+        self.turn = self.turn.wrapping_add(1);
+
+        let mut spent = vec![(0, Duration::from_secs(0)); pending.len()];
+
+        while !pending.is_empty() && !budget.is_exhausted(&usage) {
+            let index = (0..pending.len())
+                .min_by_key(|&index| {
+                    let (nodes, time) = spent[index];
+                    let spent = if budget.time.is_some() && budget.nodes.is_none() {
+                        time.as_nanos()
+                    } else {
+                        nodes as u128
+                    };
+                    (
+                        std::cmp::Reverse(self.full_paths[pending[index]].priority),
+                        spent,
+                    )
+                })
+                .unwrap();
+
+            let step_start = Instant::now();
+            let (nodes, done) = self.step_pending(pending[index]);
+            spent[index].0 += nodes;
+            spent[index].1 += step_start.elapsed();
+
+            usage.steps += 1;
+            usage.nodes += nodes;
+            usage.elapsed = start.elapsed();
+
+            if done {
+                pending.remove(index);
+                spent.remove(index);
+            }
+        }
+
+        usage
+    }
+
+    // Indices of the full paths not computed yet
+    fn pending(&self) -> Vec<usize> {
+        self.full_paths
+            .iter()
+            .enumerate()
+            .filter(|(_, full)| !full.compute.is_computed())
+            .map(|(index, _)| index)
+            .collect()
+    }
+
+    // Returns the number of nodes expanded, and whether the request is now computed
+    fn step_pending(&mut self, index: usize) -> (usize, bool) {
+        let full = &mut self.full_paths[index].compute;
+        let nodes = full.step_cost();
+        FullPathCompute::step_replace(full);
+        (nodes, full.is_computed())
+    }
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+    use crate::mapgen;
+    use crate::validation;
+
+    pub fn full_path(cost: &Field<u8>, from: CellPos, to: CellPos, mode: Mode) -> Result {
+        full_path_on(cost, Topology::default(), from, to, mode)
+    }
+
+    pub fn full_path_on(
+        cost: &Field<u8>,
+        topology: Topology,
+        from: CellPos,
+        to: CellPos,
+        mode: Mode,
+    ) -> Result {
+        let astar = AStarCompute::InitialData {
+            from,
+            to,
+            cost: CostSource::Chunks(Chunks::from_field(cost)),
+            directional: None,
+            topology,
+            min_clearance: 1,
+            search: Search::AStar,
+        };
+        let mut compute = FullPathCompute::begin(astar, mode);
+        while !compute.is_computed() {
+            compute = compute.step();
+        }
+        match compute {
+            FullPathCompute::FlowFieldComputed(result) => result,
+            _ => unreachable!(),
+        }
+    }
+
+    // The free cell nearest to the top left corner as the goal, and the cell that can reach it
+    // from the farthest as the start
+    pub fn ends(cost: &Field<u8>) -> (CellPos, CellPos) {
+        let to = cost
+            .positions()
+            .filter(|cell| *cost.get(cell) != 255)
+            .min_by_key(|cell| cell.i + cell.j)
+            .unwrap();
+        let distance = full_path(cost, to, to, Mode::WholeMap).integration();
+        let from = cost
+            .positions()
+            .filter(|cell| *distance.get(cell) != MAX_INTEGRATION)
+            .max_by_key(|cell| *distance.get(cell))
+            .unwrap();
+        (from, to)
+    }
+
+    #[test]
+    fn whole_map_is_never_costlier() {
+        for seed in 1..4 {
+            let cost = mapgen::noise(seed, 64, 64);
+            let (from, to) = ends(&cost);
+            let exact = validation::distances(&cost, &None, Topology::default(), &to);
+            let whole_map = full_path(&cost, from, to, Mode::WholeMap).stats.flow_cost;
+            let hierarchical = full_path(&cost, from, to, Mode::Hierarchical)
+                .stats
+                .flow_cost;
+            assert_eq!(whole_map, Some(*exact.get(&from)), "seed {}", seed);
+            assert!(hierarchical >= whole_map, "seed {}", seed);
+        }
+    }
+
+    const HEX: Topology = Topology {
+        grid: Grid::Hex,
+        connectivity: crate::field::Connectivity::Eight,
+        wrap: false,
+    };
+
+    #[test]
+    fn zone_cells_round_trip() {
+        let square = Topology::default();
+        let wrapped = Topology {
+            wrap: true,
+            ..square
+        };
+        let zone = Zone { zx: 2, zy: 0 };
+        let cell = (21, 7).into();
+        assert_eq!(Zone::of(&cell, square, 64), zone);
+        let local = zone.local(&cell, square, 64);
+        assert!(!Zone::is_halo(&local));
+        assert_eq!(zone.global(&local, 64, 64, square), Some(cell));
+
+        // The halo holds the cells of the neighbors, or of the other edge when the map wraps
+        let above = (HALO, 0).into();
+        assert!(Zone::is_halo(&above));
+        assert_eq!(zone.global(&above, 64, 64, square), None);
+        assert_eq!(zone.global(&above, 64, 64, wrapped), Some((16, 63).into()));
+        let right = (SECTOR_FIELD_SIZE - 1, HALO).into();
+        assert_eq!(zone.global(&right, 64, 64, square), Some((24, 0).into()));
+    }
+
+    #[test]
+    fn hex_zones_are_laid_like_bricks() {
+        // The odd rows are shifted by half a tile, their first one starts before the map
+        let cell = (2, 9).into();
+        let zone = Zone::of(&cell, HEX, 64);
+        assert_eq!(zone, Zone { zx: 0, zy: 1 });
+        assert_eq!(zone.min(HEX), (-4, 8));
+        assert_eq!(
+            zone.global(&zone.local(&cell, HEX, 64), 64, 64, HEX),
+            Some(cell)
+        );
+        assert_eq!(Zone::of(&(60, 9).into(), HEX, 64), Zone { zx: 8, zy: 1 });
+
+        // The end of the row is in the first tile when the map wraps
+        let wrapped = Topology { wrap: true, ..HEX };
+        let end = (62, 9).into();
+        assert_eq!(Zone::of(&end, wrapped, 64), zone);
+        let local = (HALO + 2, HALO + 1).into();
+        assert_eq!(zone.local(&end, wrapped, 64), local);
+        assert_eq!(zone.global(&local, 64, 64, wrapped), Some(end));
+
+        // Six neighbors along the sides, and none more at the corners
+        let zone = Zone { zx: 3, zy: 2 };
+        let mut sides: Vec<CellPos> = zone
+            .neighbors(false, 64, 64, HEX)
+            .iter()
+            .map(Zone::large_cell_pos)
+            .collect();
+        sides.sort_by_key(|zone| (zone.j, zone.i));
+        let expected: Vec<CellPos> = vec![(3, 1), (4, 1), (2, 2), (4, 2), (3, 3), (4, 3)]
+            .into_iter()
+            .map(CellPos::from)
+            .collect();
+        assert_eq!(sides, expected);
+        assert_eq!(zone.neighbors(true, 64, 64, HEX).len(), 6);
+        assert_eq!(zone.neighbors(false, 64, 64, Topology::default()).len(), 4);
+        assert_eq!(zone.neighbors(true, 64, 64, Topology::default()).len(), 8);
+    }
+
+    #[test]
+    fn hex_sectors_reach_the_goal() {
+        let cost = mapgen::noise(2, 64, 64);
+        let (from, to) = ends(&cost);
+        for topology in [HEX, Topology { wrap: true, ..HEX }].iter() {
+            let hierarchical = full_path_on(&cost, *topology, from, to, Mode::Hierarchical);
+            assert!(hierarchical.stats.sectors > 1);
+            let report = validation::validate(&hierarchical, &cost, &None, *topology, &to, 0.5);
+            assert!(report.is_valid(), "{:?}", report);
+            assert!(report.reached > 0);
+            // No sector of the corridor holds a square diagonal
+            let codes: HashSet<i8> = hierarchical.flow().iter().cloned().collect();
+            assert!(codes.iter().all(|code| *code <= topology.no_flow()));
+
+            let whole_map = full_path_on(&cost, *topology, from, to, Mode::WholeMap);
+            assert!(hierarchical.stats.flow_cost >= whole_map.stats.flow_cost);
+            assert!(hierarchical.stats.flow_cost.is_some());
+        }
+    }
+
+    #[test]
+    fn sectors_agree_across_their_borders() {
+        // Diagonal across many sector corners, the way through the halos costs what the
+        // whole map does
+        let cost = Field::new(1, 64, 64);
+        let (from, to) = ((61, 58).into(), (2, 5).into());
+        let whole_map = full_path(&cost, from, to, Mode::WholeMap);
+        let hierarchical = full_path(&cost, from, to, Mode::Hierarchical);
+        assert_eq!(hierarchical.stats.flow_cost, whole_map.stats.flow_cost);
+        assert!(hierarchical.stats.sectors > 1);
+        for chunk in hierarchical.computed.keys() {
+            let corner = (chunk.i * GRID_SIZE, chunk.j * GRID_SIZE).into();
+            assert_eq!(
+                hierarchical.integration_at(&corner),
+                whole_map.integration_at(&corner)
+            );
+        }
+    }
+
+    #[test]
+    fn clearing_a_layer_drops_only_its_requests() {
+        let mut map = Map::new(32);
+        let water = map.add_layer("water");
+        let mut computer = PathComputer::new();
+        let (from, to) = ((1, 1).into(), (30, 20).into());
+        let walk = computer.begin_full_path(from, to, &map, LayerId(0), 1);
+        let swim = computer.begin_full_path(from, to, &map, water, 1);
+        computer.clear_layer(water);
+        assert_eq!(computer.is_complete(walk), Some(false));
+        assert_eq!(computer.is_complete(swim), None);
+    }
+
+    // Nodes the A* of a request expanded so far, while it is the phase running
+    fn expanded(computer: &PathComputer, id: RequestId) -> usize {
+        let request = computer.full_paths.iter().find(|r| r.id == id).unwrap();
+        match &request.compute {
+            FullPathCompute::Astar(astar) => astar.stats().expanded,
+            _ => panic!("past the A*"),
+        }
+    }
+
+    #[test]
+    fn budget_is_shared_by_equal_priorities() {
+        let map = Map::new(64);
+        let mut computer = PathComputer::new();
+        let first = computer.begin_full_path((1, 1).into(), (62, 62).into(), &map, LayerId(0), 1);
+        let second = computer.begin_full_path((62, 1).into(), (1, 62).into(), &map, LayerId(0), 1);
+
+        // A step of an A* expands one node
+        let usage = computer.compute_with_budget(Budget {
+            nodes: Some(40),
+            time: None,
         });
+        assert_eq!(usage.nodes, 40);
+        let (first, second) = (expanded(&computer, first), expanded(&computer, second));
+        assert!(first > 0 && second > 0);
+        assert!((first as i32 - second as i32).abs() <= 1);
 
-        let all = self.astars.iter().chain(inner_astar);
+        let time = Duration::from_millis(5);
+        let usage = computer.compute_with_budget(Budget {
+            nodes: None,
+            time: Some(time),
+        });
+        assert!(usage.elapsed >= time || computer.pending().is_empty());
+        assert!(usage.elapsed < time + Duration::from_millis(100));
+    }
+
+    #[test]
+    fn higher_priority_finishes_first() {
+        let map = Map::new(64);
+        let mut computer = PathComputer::new();
+        let low = computer.begin_full_path((1, 1).into(), (62, 62).into(), &map, LayerId(0), 1);
+        let high = computer.begin_full_path((62, 1).into(), (1, 62).into(), &map, LayerId(0), 1);
+        computer.set_priority(high, 1);
+        let budget = Budget {
+            nodes: Some(10),
+            time: None,
+        };
+        while computer.is_complete(high) == Some(false) {
+            computer.compute_with_budget(budget);
+        }
+        // Only what the high priority one left of the last budget went to the other
+        assert_eq!(computer.is_complete(low), Some(false));
+        assert!(expanded(&computer, low) < 10);
+        computer.compute_all();
+        assert_eq!(computer.is_complete(low), Some(true));
+    }
+
+    #[test]
+    fn requests_are_tracked_by_id() {
+        let map = Map::new(32);
+        let mut computer = PathComputer::new();
+        let (from, to) = ((1, 1).into(), (30, 20).into());
+        let first = computer.begin_full_path(from, to, &map, LayerId(0), 1);
+        let second = computer.begin_full_path(to, from, &map, LayerId(0), 1);
+        let reserved = computer.new_id();
+        assert!(first != second && second != reserved && first != reserved);
+
+        // The highest priority takes the whole budget, the first step of an A* expands nothing
+        let budget = Budget {
+            nodes: Some(5),
+            time: None,
+        };
+        assert!(computer.set_priority(first, 1));
+        computer.compute_with_budget(budget);
+        assert_eq!(
+            (expanded(&computer, first), expanded(&computer, second)),
+            (4, 0)
+        );
+        assert!(computer.set_priority(second, 2));
+        computer.compute_with_budget(budget);
+        assert_eq!(
+            (expanded(&computer, first), expanded(&computer, second)),
+            (4, 4)
+        );
 
-        all
+        computer.compute_all();
+        assert!(computer.full_path_result(first).is_some());
+        assert!(computer.cancel(first));
+        assert!(computer.full_path_result(first).is_none());
+        assert_eq!(computer.is_complete(first), None);
+        assert!(!computer.cancel(first));
+        assert!(!computer.set_priority(reserved, 1));
+        assert!(computer.full_path_result(second).is_some());
     }
 }
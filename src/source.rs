@@ -0,0 +1,99 @@
+use crate::chunk::{self, Chunks};
+use crate::field::{CellPos, Field};
+use crate::flowfield::GRID_SIZE;
+use crate::world::World;
+use std::collections::HashSet;
+use std::sync::{Arc, Mutex};
+
+// Where a path request reads the cost of the cells. Clones share the cost, so a request keeps
+// the one it started on.
+#[derive(Clone, Debug)]
+pub enum CostSource {
+    Chunks(Chunks<u8>),
+    // A world loaded as it is searched. Given a corridor, the cells of the other chunks are
+    // neither searched nor loaded.
+    World {
+        world: Arc<Mutex<World>>,
+        corridor: Option<Arc<HashSet<CellPos>>>,
+    },
+    // A cell per chunk of a world, that costs the mean cost of the chunk
+    Coarse(Arc<Mutex<World>>),
+}
+
+impl CostSource {
+    pub fn width(&self) -> usize {
+        match self {
+            CostSource::Chunks(chunks) => chunks.width,
+            CostSource::World { world, .. } => world.lock().unwrap().width,
+            CostSource::Coarse(world) => world.lock().unwrap().width.div_ceil(GRID_SIZE),
+        }
+    }
+
+    pub fn height(&self) -> usize {
+        match self {
+            CostSource::Chunks(chunks) => chunks.height,
+            CostSource::World { world, .. } => world.lock().unwrap().height,
+            CostSource::Coarse(world) => world.lock().unwrap().height.div_ceil(GRID_SIZE),
+        }
+    }
+
+    // Loads the chunk of the cell from a world
+    pub fn get(&self, cell_pos: &CellPos) -> u8 {
+        match self {
+            CostSource::Chunks(chunks) => *chunks.get(cell_pos),
+            CostSource::World { world, .. } => world.lock().unwrap().cost_at(cell_pos),
+            CostSource::Coarse(world) => world.lock().unwrap().mean_cost(cell_pos) as u8,
+        }
+    }
+
+    // Whether a search can go through the cell
+    pub fn contains(&self, cell_pos: &CellPos) -> bool {
+        match self {
+            CostSource::World {
+                corridor: Some(corridor),
+                ..
+            } => corridor.contains(&chunk::of(cell_pos)),
+            _ => true,
+        }
+    }
+
+    // Streamed costs can't be read whole
+    pub fn is_streamed(&self) -> bool {
+        !matches!(self, CostSource::Chunks(_))
+    }
+
+    // Cells of a sector and the halo around it, see chunk::sector. The cells that are not kept
+    // are None, and not read. A world is locked once for the whole sector.
+    pub fn sector(
+        &self,
+        min: (i32, i32),
+        halo: usize,
+        wrap: bool,
+        keep: impl Fn(&CellPos) -> bool,
+    ) -> Field<Option<u8>> {
+        let keep = |cell_pos: &CellPos| keep(cell_pos) && self.contains(cell_pos);
+        match self {
+            CostSource::World { world, .. } => {
+                let mut world = world.lock().unwrap();
+                let (width, height) = (world.width, world.height);
+                chunk::sector(min, halo, width, height, wrap, |cell_pos| {
+                    if keep(cell_pos) {
+                        Some(world.cost_at(cell_pos))
+                    } else {
+                        None
+                    }
+                })
+            }
+            _ => {
+                let (width, height) = (self.width(), self.height());
+                chunk::sector(min, halo, width, height, wrap, |cell_pos| {
+                    if keep(cell_pos) {
+                        Some(self.get(cell_pos))
+                    } else {
+                        None
+                    }
+                })
+            }
+        }
+    }
+}
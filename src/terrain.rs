@@ -0,0 +1,85 @@
+use crate::directional::{self, DirectionalCost, FORBIDDEN};
+use crate::field::{Field, Topology};
+
+// Slope of each move out of a cell, in height per cell and in the order of NEIGHBORS_IJ. Moves
+// the topology doesn't have are left flat
+pub fn slope(height: &Field<f32>, topology: Topology) -> Field<[f32; 8]> {
+    Field::from_fn(height.width, height.height, |cell_pos| {
+        let h = *height.get(&cell_pos);
+        let mut slopes = [0.0; 8];
+        for (neighbor, distance) in topology.neighbors(&cell_pos, height.width, height.height) {
+            let (di, dj) = directional::step(&cell_pos, &neighbor);
+            slopes[directional::direction_index(di, dj)] =
+                (height.get(&neighbor) - h).abs() / (distance as f32 / 10.0);
+        }
+        slopes
+    })
+}
+
+// Flat moves cost nothing extra, moves steeper than max_slope are forbidden and the rest scale
+// in between, so walking along a cliff stays free while climbing it doesn't
+pub fn slope_cost(height: &Field<f32>, topology: Topology, max_slope: f32) -> DirectionalCost {
+    slope(height, topology).map(|slopes| {
+        let mut extra = [0; 8];
+        for (extra, slope) in extra.iter_mut().zip(slopes.iter()) {
+            *extra = if *slope > max_slope {
+                FORBIDDEN
+            } else {
+                (slope / max_slope * 254.0) as u8
+            };
+        }
+        extra
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::directional::direction_index;
+    use crate::field::{CellPos, Grid};
+
+    // A step of the given height between the columns 3 and 4
+    fn cliff(step: f32) -> Field<f32> {
+        Field::from_fn(8, 8, |CellPos { i, .. }| if i < 4 { 0.0 } else { step })
+    }
+
+    #[test]
+    fn slope_of_each_move() {
+        let slope = slope(&cliff(2.0), Topology::default());
+        let edge = slope.get(&(3, 3).into());
+        assert_eq!(edge[direction_index(1, 0)], 2.0);
+        assert_eq!(edge[direction_index(1, 1)], 2.0 / 1.4);
+        assert_eq!(edge[direction_index(0, 1)], 0.0);
+        assert_eq!(edge[direction_index(-1, 0)], 0.0);
+        assert_eq!(slope.get(&(6, 3).into()), &[0.0; 8]);
+
+        // Through the edge of a wrapped map, and never along the moves a hex doesn't have
+        let topology = Topology {
+            grid: Grid::Hex,
+            wrap: true,
+            ..Topology::default()
+        };
+        let slope = super::slope(&cliff(2.0), topology);
+        let edge = slope.get(&(0, 3).into());
+        assert_eq!(edge[direction_index(-1, 0)], 2.0);
+        assert_eq!(edge[direction_index(-1, -1)], 0.0);
+    }
+
+    #[test]
+    fn cliffs_block_only_the_moves_that_climb_them() {
+        let extra = slope_cost(&cliff(2.0), Topology::default(), 1.0);
+        let edge = extra.get(&(3, 3).into());
+        assert_eq!(edge[direction_index(1, 0)], FORBIDDEN);
+        assert_eq!(edge[direction_index(1, -1)], FORBIDDEN);
+        assert_eq!(edge[direction_index(0, 1)], 0);
+        assert_eq!(extra.get(&(4, 3).into())[direction_index(-1, 0)], FORBIDDEN);
+        assert_eq!(extra.get(&(4, 3).into())[direction_index(0, -1)], 0);
+
+        // Below the limit, steeper moves cost more
+        let gentle = slope_cost(&cliff(0.5), Topology::default(), 1.0);
+        let edge = gentle.get(&(3, 3).into());
+        assert_eq!(edge[direction_index(1, 0)], 127);
+        assert!(edge[direction_index(1, 1)] < 127);
+        assert!(edge[direction_index(1, 1)] > 0);
+    }
+}
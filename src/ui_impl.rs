@@ -1,4 +1,11 @@
+use crate::astar::Search;
+use crate::map::GROUND;
+use crate::mapgen::GENERATORS;
 use crate::misc::Vector2;
+use crate::paint::Tool;
+use crate::pathfinding::{Mode, RequestId, Stats};
+use crate::validation::Report;
+use crate::world::Progress;
 use ggez::event::{KeyCode, MouseButton};
 use imgui::*;
 use std::collections::HashSet;
@@ -12,18 +19,65 @@ pub enum DisplayFlowField {
 #[derive(Copy, Clone, PartialEq)]
 pub enum CursorControl {
     CostDrawing,
+    OneWayDrawing,
     TripSetting,
 }
 
+#[derive(Clone)]
+pub struct PathEntry {
+    pub id: RequestId,
+    pub priority: i32,
+    pub computed: bool,
+    // Pending on the background worker
+    pub background: bool,
+    pub stats: Option<Stats>,
+    pub delete: bool,
+    pub prioritize: bool,
+}
+
 pub struct HighLevelUI {
     pub cursor_control: CursorControl,
+    pub tool: Tool,
+    pub brush_radius: i32,
+    pub brush_cost: i32,
+    pub brush_softness: f32,
+    pub layer: usize,
+    pub layer_names: Vec<ImString>,
+    pub unit_clearance: i32,
+    pub show_clearance: bool,
+    pub crowd_avoidance: bool,
+    pub discomfort: f32,
+    pub repath_interval: i32,
+    pub repath_on_edit: bool,
+    pub show_congestion: bool,
     pub flowfield_mode: DisplayFlowField,
     pub flowfield_show_arrow: bool,
     pub compute_live: bool,
     pub compute_step: bool,
-    pub set_demo: bool,
+    pub generator: usize,
+    pub generator_names: Vec<ImString>,
+    pub seed: i32,
+    pub generate: bool,
+    pub undo: bool,
+    pub redo: bool,
+    pub undo_count: usize,
+    pub redo_count: usize,
+    pub set_terrain: bool,
+    pub max_slope: f32,
+    pub shade_height: bool,
+    pub wrap: bool,
+    pub hex_grid: bool,
+    pub diagonal_moves: bool,
+    pub search: Search,
+    pub mode: Mode,
     pub step_per_frame: i32,
+    pub use_budget: bool,
+    pub budget_ms: f32,
+    pub budget_nodes: i32,
+    pub last_budget_nodes: usize,
     pub compute_all: bool,
+    pub compute_background: bool,
+    pub background_jobs: usize,
     pub mouse_pos: Vector2,
     pub keys_pressed: HashSet<KeyCode>,
     pub keys_triggered: HashSet<KeyCode>,
@@ -37,21 +91,64 @@ pub struct HighLevelUI {
     pub last_compute_ms: u128,
     pub auto_delete: bool,
 
-    pub full_pathfinding: Vec<(bool, String)>,
+    pub full_pathfinding: Vec<PathEntry>,
     pub number_of_agent: usize,
+    pub cost_to_goal: Option<i32>,
+    pub validate_flow: bool,
+    pub flow_report: Option<Report>,
+    pub cross_world: bool,
+    pub world_capacity: i32,
+    pub world_progress: Option<Progress>,
 }
 
 impl HighLevelUI {
     pub fn new() -> HighLevelUI {
         HighLevelUI {
             cursor_control: CursorControl::CostDrawing,
+            tool: Tool::Brush,
+            brush_radius: 1,
+            brush_cost: 255,
+            brush_softness: 0.0,
+            layer: GROUND.0,
+            layer_names: Vec::new(),
+            unit_clearance: 1,
+            show_clearance: false,
+            crowd_avoidance: false,
+            discomfort: 8.0,
+            repath_interval: 60,
+            repath_on_edit: false,
+            show_congestion: false,
             flowfield_mode: DisplayFlowField::Cost,
             flowfield_show_arrow: false,
             compute_live: true,
             compute_step: false,
-            set_demo: false,
+            generator: 0,
+            generator_names: GENERATORS
+                .iter()
+                .map(|generator| ImString::new(generator.name()))
+                .collect(),
+            seed: 0,
+            generate: false,
+            undo: false,
+            redo: false,
+            undo_count: 0,
+            redo_count: 0,
+            set_terrain: false,
+            max_slope: 0.5,
+            shade_height: true,
+            wrap: false,
+            hex_grid: false,
+            diagonal_moves: true,
+            search: Search::AStar,
+            mode: Mode::Hierarchical,
             step_per_frame: 2,
+            use_budget: false,
+            budget_ms: 2.0,
+            budget_nodes: 0,
+            last_budget_nodes: 0,
             compute_all: true,
+            compute_background: false,
+            background_jobs: 0,
             mouse_pos: Vector2::new(0.0, 0.0),
             keys_pressed: HashSet::new(),
             keys_triggered: HashSet::new(),
@@ -66,6 +163,12 @@ impl HighLevelUI {
             auto_delete: true,
             full_pathfinding: Vec::new(),
             number_of_agent: 0,
+            cost_to_goal: None,
+            validate_flow: false,
+            flow_report: None,
+            cross_world: false,
+            world_capacity: 1024,
+            world_progress: None,
         }
     }
 
@@ -96,33 +199,120 @@ impl HighLevelUI {
             Window::new(im_str!("Rust field"))
             .size([300.0, 400.0], imgui::Condition::FirstUseEver)
             .build(ui, || {
-                                ui.text(im_str!("Try the "));
-                                ui.same_line(0.0);
-                                if ui.small_button(im_str!("Demo")) {
-                                    self.set_demo=  true;
+                                let generator_names: Vec<&ImString> = self.generator_names.iter().collect();
+                                ComboBox::new(im_str!("Map")).build_simple_string(ui, &mut self.generator, &generator_names);
+                                ui.input_int(im_str!("Seed"), &mut self.seed).build();
+                                if ui.small_button(im_str!("Generate")) {
+                                    self.generate = true;
                                 };
-                                ui.text(im_str!("Draw a maze"));
+                                if ui.is_item_hovered(){
+                                    ui.tooltip_text(im_str!("Replaces the cost of the current layer"));
+                                }
+                                ui.text(im_str!("Or draw a maze"));
                                 ui.text(im_str!("Then check the 'Trip setting'"));
 
+                                if ui.small_button(im_str!("Terrain")) {
+                                    self.set_terrain = true;
+                                };
+                                ui.same_line(0.0);
+                                imgui::Slider::new(im_str!("max slope "),0.05..=2.0).build(ui, &mut self.max_slope);
+                                if ui.is_item_hovered(){
+                                    ui.tooltip_text(im_str!("Height per cell above which a slope can't be climbed"));
+                                }
+
                                 ui.separator();
 
+                                ui.checkbox(im_str!("Wrap around"), &mut self.wrap);
+                                if ui.is_item_hovered(){
+                                    ui.tooltip_text(im_str!("Units can cross an edge of the map to reach the opposite one"));
+                                }
+                                ui.same_line(0.0);
+                                ui.checkbox(im_str!("Hex grid"), &mut self.hex_grid);
+                                if ui.is_item_hovered(){
+                                    ui.tooltip_text(im_str!("Cells are hexes in axial coordinates, drawn sheared into squares: a cell has no neighbor on its top left and bottom right. Sectors are laid like bricks, every other row shifted by half a sector, so that each touches six others, and flows point along the six moves."));
+                                }
+                                if !self.hex_grid {
+                                    ui.same_line(0.0);
+                                    ui.checkbox(im_str!("Diagonals"), &mut self.diagonal_moves);
+                                    if ui.is_item_hovered(){
+                                        ui.tooltip_text(im_str!("Unchecked, units only move orthogonally"));
+                                    }
+                                }
+
+                                let layer_names: Vec<&ImString> = self.layer_names.iter().collect();
+                                ComboBox::new(im_str!("Cost layer")).build_simple_string(ui, &mut self.layer, &layer_names);
+                                if ui.is_item_hovered(){
+                                    ui.tooltip_text(im_str!("Layer drawn, used by new trips and by spawned agents"));
+                                }
+                                imgui::Slider::new(im_str!("Unit clearance"),1..=8).build(ui, &mut self.unit_clearance,);
+                                if ui.is_item_hovered(){
+                                    ui.tooltip_text(im_str!("Cells needed between the center of a unit and a wall, used by new trips and by spawned agents"));
+                                }
+
+                                ui.separator();
+
+                                if ui.small_button(im_str!("Undo ({})", self.undo_count).as_ref()) {
+                                    self.undo = true;
+                                }
+                                ui.same_line(0.0);
+                                if ui.small_button(im_str!("Redo ({})", self.redo_count).as_ref()) {
+                                    self.redo = true;
+                                }
+
                                 ui.text(im_str!("Control: "));
                                 ui.radio_button(im_str!("Cost drawing"),&mut self.cursor_control,CursorControl::CostDrawing);
                                 ui.same_line(0.0);
+                                ui.radio_button(im_str!("One way"),&mut self.cursor_control,CursorControl::OneWayDrawing);
+                                ui.same_line(0.0);
                                 ui.radio_button(im_str!("Trip setting"),&mut self.cursor_control,CursorControl::TripSetting);
 
                                 match self.cursor_control{
                                     CursorControl::CostDrawing =>{
-                                        ui.bullet_text(im_str!("Left click : Wall"));
-                                        ui.bullet_text(im_str!("Right click : Erase"));
+                                        ui.radio_button(im_str!("Brush"),&mut self.tool,Tool::Brush);
+                                        ui.same_line(0.0);
+                                        ui.radio_button(im_str!("Line"),&mut self.tool,Tool::Line);
+                                        ui.same_line(0.0);
+                                        ui.radio_button(im_str!("Rectangle"),&mut self.tool,Tool::Rectangle);
+                                        ui.same_line(0.0);
+                                        ui.radio_button(im_str!("Fill"),&mut self.tool,Tool::Fill);
+                                        imgui::Slider::new(im_str!("Cost"),1..=255).build(ui, &mut self.brush_cost,);
+                                        if ui.is_item_hovered(){
+                                            ui.tooltip_text(im_str!("255 is a wall"));
+                                        }
+                                        if self.tool == Tool::Brush || self.tool == Tool::Line {
+                                            imgui::Slider::new(im_str!("Brush radius"),0..=16).build(ui, &mut self.brush_radius,);
+                                            imgui::Slider::new(im_str!("Softness"),0.0..=1.0).build(ui, &mut self.brush_softness,);
+                                            if ui.is_item_hovered(){
+                                                ui.tooltip_text(im_str!("Part of the radius fading out, the edge blends into the cost under it"));
+                                            }
+                                        }
+                                        match self.tool {
+                                            Tool::Brush => {
+                                                ui.bullet_text(im_str!("Left click : Paint"));
+                                                ui.bullet_text(im_str!("Right click : Erase"));
+                                            }
+                                            Tool::Line | Tool::Rectangle => {
+                                                ui.bullet_text(im_str!("Left drag : Paint"));
+                                                ui.bullet_text(im_str!("Right drag : Erase"));
+                                            }
+                                            Tool::Fill => {
+                                                ui.bullet_text(im_str!("Left click : Fill the area of same cost"));
+                                                ui.bullet_text(im_str!("Right click : Erase the area"));
+                                            }
+                                        }
                                         ui.bullet_text(im_str!("Middle click : Reset"));
                                     }
+                                    CursorControl::OneWayDrawing =>{
+                                        ui.bullet_text(im_str!("Left drag : Forbid moving against the drag"));
+                                        ui.bullet_text(im_str!("Right click : Erase"));
+                                    }
                                     CursorControl::TripSetting =>{
                                         ui.bullet_text(im_str!("Left click : Place start"));
                                         ui.bullet_text(im_str!("Right click : Place end"));
                                         ui.bullet_text(im_str!("Middle click : Reset path"));
                                     }
                                 }
+                                ui.bullet_text(im_str!("Ctrl+Z / Ctrl+Y : Undo / Redo"));
                                 ui.bullet_text(im_str!("ZQSD : Pan camera"));
                                 ui.bullet_text(im_str!("Scroll : Zoom camera"));
                                 ui.bullet_text(im_str!("Space : Spawn 250 agents"));
@@ -144,9 +334,38 @@ impl HighLevelUI {
                                     );
                                 }
 
+                                ui.checkbox(im_str!("Show clearance"), &mut self.show_clearance);
+                                ui.checkbox(im_str!("Show congestion"), &mut self.show_congestion);
+                                ui.checkbox(im_str!("Shade height"), &mut self.shade_height);
+
                                 ui.separator();
                                 ui.text(im_str!("Computations: "));
                                 ui.checkbox(im_str!("Auto delete old path"), &mut self.auto_delete);
+                                ui.radio_button(im_str!("A*"),&mut self.search,Search::AStar);
+                                ui.same_line(0.0);
+                                ui.radio_button(im_str!("JPS"),&mut self.search,Search::Jps);
+                                if ui.is_item_hovered(){
+                                    ui.tooltip_text(im_str!("Faster on maps where every free cell has the same cost, plain A* is used on the others"));
+                                }
+                                ui.same_line(0.0);
+                                ui.radio_button(im_str!("Theta*"),&mut self.search,Search::ThetaStar);
+                                if ui.is_item_hovered(){
+                                    ui.tooltip_text(im_str!("Any-angle path, its waypoints drawn in white over the sectors"));
+                                }
+                                ui.same_line(0.0);
+                                ui.radio_button(im_str!("Both ways"),&mut self.search,Search::Bidirectional);
+                                if ui.is_item_hovered(){
+                                    ui.tooltip_text(im_str!("Bidirectional A*, searches from the goal too until both sides meet"));
+                                }
+                                ui.radio_button(im_str!("Sectors"),&mut self.mode,Mode::Hierarchical);
+                                if ui.is_item_hovered(){
+                                    ui.tooltip_text(im_str!("Flow fields only on the sectors along the A* path"));
+                                }
+                                ui.same_line(0.0);
+                                ui.radio_button(im_str!("Whole map"),&mut self.mode,Mode::WholeMap);
+                                if ui.is_item_hovered(){
+                                    ui.tooltip_text(im_str!("A single flow field over the whole map, slower but never misses a cheaper way"));
+                                }
                                 ui.checkbox(im_str!("Compute all instantly"), &mut self.compute_all);
                                 ui.same_line(0.0);
                                 ui.text(im_str!(
@@ -155,12 +374,34 @@ impl HighLevelUI {
                                 ));
 
 
+                                ui.checkbox(im_str!("Compute in background"), &mut self.compute_background);
+                                if self.compute_background {
+                                    ui.same_line(0.0);
+                                    ui.text(im_str!(
+                                        "/ {} pending",
+                                        self.background_jobs
+                                    ));
+                                }
+
                                 if !self.compute_all {
                                     ui.checkbox(im_str!("Compute live"), &mut self.compute_live);
                                 }
 
                                 if !self.compute_all  && self.compute_live{
-                                    imgui::Slider::new(im_str!("step per frame "),0..=100).build(ui, &mut self.step_per_frame,);
+                                    ui.checkbox(im_str!("Time budget"), &mut self.use_budget);
+                                    if self.use_budget {
+                                        imgui::Slider::new(im_str!("ms per frame "),0.1..=16.0).build(ui, &mut self.budget_ms,);
+                                        imgui::Slider::new(im_str!("nodes per frame "),0..=10000).build(ui, &mut self.budget_nodes,);
+                                        if ui.is_item_hovered(){
+                                            ui.tooltip_text(im_str!("0 : no node limit"));
+                                        }
+                                        ui.text(im_str!(
+                                            "{} nodes expanded last frame",
+                                            self.last_budget_nodes
+                                        ));
+                                    } else {
+                                        imgui::Slider::new(im_str!("step per frame "),0..=100).build(ui, &mut self.step_per_frame,);
+                                    }
                                 }
 
                                 if !self.compute_live && !self.compute_all {
@@ -171,19 +412,139 @@ impl HighLevelUI {
                                 ui.separator();
 
                                 ui.text(im_str!("Path list: "));
-                                for (index,e) in self.full_pathfinding.iter_mut().enumerate() {
+                                for e in self.full_pathfinding.iter_mut() {
                                     ui.text(im_str!(
-                                    "path#{}",
-                                    index
+                                    "path#{} ({})",
+                                    e.id,
+                                    if e.computed { "done" } else if e.background { "in background" } else { "computing" }
                                 ));
                                     ui.same_line(0.0);
-                                    if ui.small_button(im_str!("Delete##{}",index).as_ref()) {
-                                        e.0 = true;
+                                    if ui.small_button(im_str!("Delete##{}",e.id).as_ref()) {
+                                        e.delete = true;
+                                    }
+                                    if !e.computed {
+                                        ui.same_line(0.0);
+                                        if ui.small_button(im_str!("Priority {}##{}",e.priority,e.id).as_ref()) {
+                                            e.prioritize = true;
+                                        }
+                                    }
+                                    if let Some(stats) = &e.stats {
+                                        ui.text(im_str!(
+                                            "  A*: cost {}, {} cells, {} nodes, {} us",
+                                            stats.astar.cost,
+                                            stats.astar.length,
+                                            stats.astar.expanded,
+                                            stats.astar.elapsed.as_micros()
+                                        ));
+                                        ui.text(im_str!(
+                                            "  sectors: {} found in {} us",
+                                            stats.sectors,
+                                            stats.sector_finding.elapsed.as_micros()
+                                        ));
+                                        ui.text(im_str!(
+                                            "  flow fields: {} steps, {} nodes, {} us",
+                                            stats.flow_fields.steps,
+                                            stats.flow_fields.nodes,
+                                            stats.flow_fields.elapsed.as_micros()
+                                        ));
+                                        if let Some(cost) = stats.flow_cost {
+                                            ui.text(im_str!("  cost from the start: {}", cost));
+                                        }
+                                    }
+                                }
+
+                                if let Some(cost) = self.cost_to_goal {
+                                    ui.text(im_str!("Cost to goal under the cursor: {}", cost));
+                                }
+                                if ui.small_button(im_str!("Validate flow")) {
+                                    self.validate_flow = true;
+                                }
+                                if ui.is_item_hovered(){
+                                    ui.tooltip_text(im_str!("Follows the flow of the path of the current layer from every cell. Loops are red, dead ends orange, moves into walls magenta and suboptimal cells yellow."));
+                                }
+                                if let Some(report) = &self.flow_report {
+                                    ui.text(im_str!(
+                                        "{} cells checked, {} reach the goal",
+                                        report.checked,
+                                        report.reached
+                                    ));
+                                    ui.text(im_str!(
+                                        "loops {}, dead ends {}, blocked {}",
+                                        report.loops.len(),
+                                        report.dead_ends.len(),
+                                        report.blocked.len()
+                                    ));
+                                    ui.text(im_str!(
+                                        "suboptimal {}, cost x{:.3} on average, x{:.3} at worst",
+                                        report.suboptimal.len(),
+                                        report.mean_ratio,
+                                        report.worst_ratio
+                                    ));
+                                    if ui.small_button(im_str!("Hide report")) {
+                                        self.flow_report = None;
                                     }
                                 }
 
                                 ui.separator();
                                 ui.text(im_str!("Agents: {}", self.number_of_agent));
+                                ui.checkbox(im_str!("Crowd avoidance"), &mut self.crowd_avoidance);
+                                if ui.is_item_hovered(){
+                                    ui.tooltip_text(im_str!("Trips are computed again from time to time, on a cost raised where agents crowd"));
+                                }
+                                if self.crowd_avoidance {
+                                    imgui::Slider::new(im_str!("discomfort per agent"),0.0..=64.0).build(ui, &mut self.discomfort,);
+                                    imgui::Slider::new(im_str!("frames between repaths"),1..=300).build(ui, &mut self.repath_interval,);
+                                }
+                                ui.checkbox(im_str!("Repath on edit"), &mut self.repath_on_edit);
+                                if ui.is_item_hovered(){
+                                    ui.tooltip_text(im_str!("Trips are computed again when the map is edited under their path"));
+                                }
+
+                                ui.separator();
+                                imgui::Slider::new(im_str!("chunks loaded at most"),64..=16384).build(ui, &mut self.world_capacity,);
+                                if ui.small_button(im_str!("Cross the world")) {
+                                    self.cross_world = true;
+                                }
+                                if ui.is_item_hovered(){
+                                    ui.tooltip_text(im_str!("Computes a path from corner to corner of a 16384x16384 noise terrain, loaded by chunks as the search reaches them, then an agent walks it. On the background worker when computing in background"));
+                                }
+                                if let Some(progress) = &self.world_progress {
+                                    if let Some(id) = progress.id {
+                                        ui.text(im_str!("request {}", id));
+                                    }
+                                    ui.text(im_str!(
+                                        "chunks: {} loaded, {} at most, {} loads, {} evictions",
+                                        progress.loaded,
+                                        progress.chunks.peak,
+                                        progress.chunks.loads,
+                                        progress.chunks.evictions
+                                    ));
+                                    match &progress.stats {
+                                        None => ui.text(im_str!(
+                                            "  searching, {} chunks then {} cells",
+                                            progress.coarse,
+                                            progress.expanded
+                                        )),
+                                        Some(stats) => ui.text(im_str!(
+                                            "  coarse A* {} chunks, {} ms, A* {} nodes, {} cells, {} ms, {} sectors, cost {}",
+                                            stats.coarse.expanded,
+                                            stats.coarse.elapsed.as_millis(),
+                                            stats.astar.expanded,
+                                            stats.astar.length,
+                                            stats.astar.elapsed.as_millis(),
+                                            stats.sectors,
+                                            stats.flow_cost.unwrap_or(-1)
+                                        )),
+                                    }
+                                    if let Some((cell_pos, cost_to_goal)) = progress.walker {
+                                        ui.text(im_str!(
+                                            "  walker at {}, {}, cost to goal {}",
+                                            cell_pos.i,
+                                            cell_pos.j,
+                                            cost_to_goal.unwrap_or(-1)
+                                        ));
+                                    }
+                                }
 
 
                             });
@@ -0,0 +1,259 @@
+use crate::directional::{self, DirectionalCost};
+use crate::field::{CellPos, Field, Topology};
+use crate::flowfield::MAX_INTEGRATION;
+use crate::pathfinding;
+use std::cmp::Reverse;
+use std::collections::BinaryHeap;
+
+// Where the walk from a cell ended
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+enum Walk {
+    Unknown,
+    // On the walk being followed
+    InProgress,
+    // With the cost of the walk
+    Goal(i32),
+    Loop,
+    DeadEnd,
+    Blocked,
+}
+
+enum Step {
+    To(CellPos, i32),
+    DeadEnd,
+    Blocked,
+}
+
+// The offending cells are where the walks go wrong, the cells leading to them only count as
+// not reaching the goal
+#[derive(Clone, Debug, Default)]
+pub struct Report {
+    // Cells with a cost to the goal, a walk starts from each of them
+    pub checked: usize,
+    pub reached: usize,
+    // Cells of the cycles the flow goes around
+    pub loops: Vec<CellPos>,
+    // No direction, or one leaving the computed sectors
+    pub dead_ends: Vec<CellPos>,
+    // The direction leads into a wall, out of the map or through a forbidden move
+    pub blocked: Vec<CellPos>,
+    // Reached the goal at more than the tolerance above the exact cost
+    pub suboptimal: Vec<CellPos>,
+    // Cost walked over the exact cost, for the cells other than the goal that reached it
+    pub mean_ratio: f32,
+    pub worst_ratio: f32,
+}
+
+impl Report {
+    pub fn is_valid(&self) -> bool {
+        self.loops.is_empty() && self.dead_ends.is_empty() && self.blocked.is_empty()
+    }
+}
+
+// Follows the flow of the result from every cell that has a cost to the goal. The cost and
+// directional cost must be the ones the result was computed on.
+pub fn validate(
+    result: &pathfinding::Result,
+    cost: &Field<u8>,
+    directional: &Option<DirectionalCost>,
+    topology: Topology,
+    goal: &CellPos,
+    tolerance: f32,
+) -> Report {
+    let exact = distances(cost, directional, topology, goal);
+    let (integration, flow) = (result.integration(), result.flow());
+    let mut walks = Field::new(Walk::Unknown, cost.width, cost.height);
+    let mut report = Report::default();
+
+    for start in cost.positions() {
+        if *cost.get(&start) == 255 || *integration.get(&start) == MAX_INTEGRATION {
+            continue;
+        }
+        report.checked += 1;
+
+        // Cells left with the cost of their step, until one whose walk is known
+        let mut walked: Vec<(CellPos, i32)> = Vec::new();
+        let mut cell = start;
+        let mut end = loop {
+            match *walks.get(&cell) {
+                Walk::Unknown => {}
+                Walk::InProgress => {
+                    let first = walked.iter().position(|(c, _)| *c == cell).unwrap();
+                    report.loops.extend(walked[first..].iter().map(|(c, _)| *c));
+                    break Walk::Loop;
+                }
+                known => break known,
+            }
+            walks.set(&cell, Walk::InProgress);
+            if cell == *goal {
+                walks.set(&cell, Walk::Goal(0));
+                break Walk::Goal(0);
+            }
+            match step(&flow, cost, directional, topology, &cell) {
+                Step::To(next, step_cost) => {
+                    walked.push((cell, step_cost));
+                    cell = next;
+                }
+                Step::DeadEnd => {
+                    report.dead_ends.push(cell);
+                    walks.set(&cell, Walk::DeadEnd);
+                    break Walk::DeadEnd;
+                }
+                Step::Blocked => {
+                    report.blocked.push(cell);
+                    walks.set(&cell, Walk::Blocked);
+                    break Walk::Blocked;
+                }
+            }
+        };
+
+        for (cell, step_cost) in walked.iter().rev() {
+            if let Walk::Goal(walk_cost) = end {
+                end = Walk::Goal(walk_cost + step_cost);
+            }
+            walks.set(cell, end);
+        }
+    }
+
+    let mut ratio_sum = 0.0;
+    let mut ratios = 0;
+    for (cell, walk) in walks.cells() {
+        if let Walk::Goal(walk_cost) = walk {
+            report.reached += 1;
+            let exact = *exact.get(&cell);
+            if exact == 0 || exact == MAX_INTEGRATION {
+                continue;
+            }
+            let ratio = *walk_cost as f32 / exact as f32;
+            ratio_sum += ratio;
+            ratios += 1;
+            report.worst_ratio = report.worst_ratio.max(ratio);
+            if ratio > 1.0 + tolerance {
+                report.suboptimal.push(cell);
+            }
+        }
+    }
+    if ratios > 0 {
+        report.mean_ratio = ratio_sum / ratios as f32;
+    }
+    report
+}
+
+// The move given by the flow of a cell, and its cost as the integration counts it
+fn step(
+    flow: &Field<i8>,
+    cost: &Field<u8>,
+    directional: &Option<DirectionalCost>,
+    topology: Topology,
+    cell: &CellPos,
+) -> Step {
+    let (di, dj) = match topology.flow_step(*flow.get(cell)) {
+        Some(step) => step,
+        None => return Step::DeadEnd,
+    };
+    let extra = match directional::extra_cost(directional, cell, di, dj) {
+        Some(extra) if topology.connects(di, dj) => extra,
+        _ => return Step::Blocked,
+    };
+
+    let (w, h) = (cost.width as i32, cost.height as i32);
+    let (i, j) = (cell.i as i32 + di, cell.j as i32 + dj);
+    let next = if topology.wrap {
+        (i.rem_euclid(w), j.rem_euclid(h))
+    } else if i < 0 || j < 0 || i >= w || j >= h {
+        return Step::Blocked;
+    } else {
+        (i, j)
+    };
+    let next = CellPos {
+        i: next.0 as usize,
+        j: next.1 as usize,
+    };
+    if *cost.get(&next) == 255 {
+        return Step::Blocked;
+    }
+
+    let step_cost = (*cost.get(cell) as i32 + extra) * topology.step_distance(di, dj) as i32;
+    Step::To(next, step_cost)
+}
+
+// Exact cost to the goal from every cell, Dijkstra with the rule of the flow field integration.
+// MAX_INTEGRATION where the goal can't be reached.
+pub fn distances(
+    cost: &Field<u8>,
+    directional: &Option<DirectionalCost>,
+    topology: Topology,
+    goal: &CellPos,
+) -> Field<i32> {
+    let mut distance = Field::new(MAX_INTEGRATION, cost.width, cost.height);
+    distance.set(goal, 0);
+    let mut open = BinaryHeap::new();
+    open.push(Reverse((0, goal.i, goal.j)));
+
+    while let Some(Reverse((current, i, j))) = open.pop() {
+        let visit = CellPos { i, j };
+        if current > *distance.get(&visit) {
+            continue;
+        }
+        for (neighbor, step_distance) in topology.neighbors(&visit, cost.width, cost.height) {
+            let cost_of_neighbor = *cost.get(&neighbor);
+            if cost_of_neighbor == 255 {
+                continue;
+            }
+            // Backward, agents move from the neighbor to this cell
+            let (di, dj) = directional::step(&neighbor, &visit);
+            if let Some(extra) = directional::extra_cost(directional, &neighbor, di, dj) {
+                let new_distance =
+                    current + (cost_of_neighbor as i32 + extra) * step_distance as i32;
+                if new_distance < *distance.get(&neighbor) {
+                    distance.set(&neighbor, new_distance);
+                    open.push(Reverse((new_distance, neighbor.i, neighbor.j)));
+                }
+            }
+        }
+    }
+    distance
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mapgen::GENERATORS;
+    use crate::pathfinding::tests::{ends, full_path};
+    use crate::pathfinding::Mode;
+
+    #[test]
+    fn generated_maps_have_valid_flows() {
+        for generator in GENERATORS.iter() {
+            for seed in 1..4 {
+                let cost = generator.generate(seed, 64, 64);
+                let (from, to) = ends(&cost);
+                for mode in [Mode::Hierarchical, Mode::WholeMap].iter() {
+                    let result = full_path(&cost, from, to, *mode);
+                    let report = validate(&result, &cost, &None, Topology::default(), &to, 0.0);
+                    let what = (generator.name(), seed, *mode);
+                    assert!(report.loops.is_empty(), "{:?}", what);
+                    assert!(report.dead_ends.is_empty(), "{:?}", what);
+                    assert!(report.blocked.is_empty(), "{:?}", what);
+                    assert_eq!(report.reached, report.checked, "{:?}", what);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn uniform_flow_is_exact() {
+        let cost = Field::new(1, 64, 64);
+        let (from, to) = ((60, 41).into(), (3, 3).into());
+        let whole_map = full_path(&cost, from, to, Mode::WholeMap);
+        let report = validate(&whole_map, &cost, &None, Topology::default(), &to, 0.0);
+        assert!(report.suboptimal.is_empty());
+        assert_eq!(report.worst_ratio, 1.0);
+
+        // The corridor can make cells away from the path walk around it, not the start
+        let hierarchical = full_path(&cost, from, to, Mode::Hierarchical);
+        let report = validate(&hierarchical, &cost, &None, Topology::default(), &to, 0.0);
+        assert!(report.is_valid());
+        assert!(!report.suboptimal.contains(&from));
+    }
+}
@@ -0,0 +1,312 @@
+use crate::astar::{AStarCompute, Search};
+use crate::field::CellPos;
+use crate::map::{LayerId, Map};
+use crate::pathfinding::{self, FullPathCompute, Mode, RequestId};
+use crate::source::CostSource;
+use crate::world::World;
+use std::sync::atomic::{AtomicBool, AtomicI32, Ordering};
+use std::sync::mpsc::{channel, Receiver, RecvTimeoutError, Sender};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+struct Job {
+    compute: FullPathCompute,
+    cancelled: Arc<AtomicBool>,
+    priority: Arc<AtomicI32>,
+    sender: Sender<pathfinding::Result>,
+}
+
+pub enum JobStatus {
+    Pending,
+    Done(Box<pathfinding::Result>),
+    Cancelled,
+}
+
+pub struct JobHandle {
+    id: RequestId,
+    receiver: Receiver<pathfinding::Result>,
+    cancelled: Arc<AtomicBool>,
+    priority: Arc<AtomicI32>,
+}
+
+impl JobHandle {
+    pub fn id(&self) -> RequestId {
+        self.id
+    }
+
+    // Never blocks, the UI polls its jobs once per update
+    pub fn poll(&self) -> JobStatus {
+        self.wait(Duration::from_secs(0))
+    }
+
+    // Blocks until the worker delivers the result or the timeout passes. A job cancelled while
+    // waited for ends the wait.
+    pub fn wait(&self, timeout: Duration) -> JobStatus {
+        if self.is_cancelled() {
+            return JobStatus::Cancelled;
+        }
+        match self.receiver.recv_timeout(timeout) {
+            Ok(result) => JobStatus::Done(Box::new(result)),
+            Err(RecvTimeoutError::Timeout) => JobStatus::Pending,
+            Err(RecvTimeoutError::Disconnected) => JobStatus::Cancelled,
+        }
+    }
+
+    pub fn cancel(&self) {
+        self.cancelled.store(true, Ordering::Relaxed);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.cancelled.load(Ordering::Relaxed)
+    }
+
+    pub fn priority(&self) -> i32 {
+        self.priority.load(Ordering::Relaxed)
+    }
+
+    // Like the priority of a PathComputer request, jobs of a lower one wait
+    pub fn set_priority(&self, priority: i32) {
+        self.priority.store(priority, Ordering::Relaxed);
+    }
+}
+
+pub struct PathWorker {
+    // Used by the jobs submitted from now on
+    pub search: Search,
+    pub mode: Mode,
+    sender: Option<Sender<Job>>,
+    shutdown: Arc<AtomicBool>,
+    thread: Option<thread::JoinHandle<()>>,
+}
+
+impl PathWorker {
+    pub fn new() -> Self {
+        let (sender, receiver) = channel();
+        let shutdown = Arc::new(AtomicBool::new(false));
+        let thread_shutdown = shutdown.clone();
+        let thread = thread::Builder::new()
+            .name(String::from("path worker"))
+            .spawn(move || PathWorker::run(receiver, thread_shutdown))
+            .unwrap();
+
+        PathWorker {
+            search: Search::AStar,
+            mode: Mode::Hierarchical,
+            sender: Some(sender),
+            shutdown,
+            thread: Some(thread),
+        }
+    }
+
+    // The id comes from the path computer the result is handed over to, see
+    // PathComputer::new_id
+    pub fn submit(
+        &self,
+        id: RequestId,
+        from: CellPos,
+        to: CellPos,
+        map: &Map,
+        layer: LayerId,
+        min_clearance: u8,
+    ) -> JobHandle {
+        let compute = FullPathCompute::begin(
+            AStarCompute::InitialData {
+                from,
+                to,
+                cost: CostSource::Chunks(map.chunks(layer).clone()),
+                directional: map.directional(layer).clone(),
+                topology: map.topology,
+                min_clearance,
+                search: self.search,
+            },
+            self.mode,
+        );
+        self.submit_compute(id, compute)
+    }
+
+    // The world is shared with the worker, which loads its chunks as it searches
+    pub fn submit_world(
+        &self,
+        id: RequestId,
+        from: CellPos,
+        to: CellPos,
+        world: Arc<Mutex<World>>,
+    ) -> JobHandle {
+        let compute = FullPathCompute::across_world(from, to, world, self.search);
+        self.submit_compute(id, compute)
+    }
+
+    fn submit_compute(&self, id: RequestId, compute: FullPathCompute) -> JobHandle {
+        let (sender, receiver) = channel();
+        let cancelled = Arc::new(AtomicBool::new(false));
+        let priority = Arc::new(AtomicI32::new(0));
+        let job = Job {
+            compute,
+            cancelled: cancelled.clone(),
+            priority: priority.clone(),
+            sender,
+        };
+
+        if let Some(jobs) = &self.sender {
+            // If the worker is gone the job is dropped, and the handle sees it as cancelled
+            let _ = jobs.send(job);
+        }
+
+        JobHandle {
+            id,
+            receiver,
+            cancelled,
+            priority,
+        }
+    }
+
+    // Every pending job of the highest priority is advanced by one step in turn, so a long
+    // request does not starve the ones submitted after it
+    fn run(receiver: Receiver<Job>, shutdown: Arc<AtomicBool>) {
+        let mut jobs: Vec<Job> = Vec::new();
+
+        while !shutdown.load(Ordering::Relaxed) {
+            if jobs.is_empty() {
+                match receiver.recv() {
+                    Ok(job) => jobs.push(job),
+                    Err(_) => return,
+                }
+            }
+            jobs.extend(receiver.try_iter());
+
+            jobs.retain(|job| !job.cancelled.load(Ordering::Relaxed));
+
+            let top = jobs
+                .iter()
+                .map(|job| job.priority.load(Ordering::Relaxed))
+                .max();
+            for job in &mut jobs {
+                if Some(job.priority.load(Ordering::Relaxed)) == top {
+                    FullPathCompute::step_replace(&mut job.compute);
+                }
+            }
+
+            let (done, pending): (Vec<Job>, Vec<Job>) =
+                jobs.into_iter().partition(|job| job.compute.is_computed());
+            jobs = pending;
+
+            for job in done {
+                if let FullPathCompute::FlowFieldComputed(result) = job.compute {
+                    // The handle may have been dropped in the meantime
+                    let _ = job.sender.send(result);
+                }
+            }
+        }
+    }
+}
+
+impl Drop for PathWorker {
+    fn drop(&mut self) {
+        self.shutdown.store(true, Ordering::Relaxed);
+        self.sender = None;
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::pathfinding::PathComputer;
+    use std::time::Instant;
+
+    fn poll_until_done(handle: &JobHandle) -> JobStatus {
+        let start = Instant::now();
+        loop {
+            match handle.poll() {
+                JobStatus::Pending if start.elapsed() < Duration::from_secs(30) => {
+                    thread::sleep(Duration::from_millis(1))
+                }
+                status => return status,
+            }
+        }
+    }
+
+    #[test]
+    fn submitted_jobs_complete() {
+        let worker = PathWorker::new();
+        let mut ids = PathComputer::new();
+        let map = Map::new(64);
+        let id = ids.new_id();
+        let handle = worker.submit(id, (2, 3).into(), (60, 50).into(), &map, LayerId(0), 1);
+        assert_eq!(handle.id(), id);
+        match poll_until_done(&handle) {
+            JobStatus::Done(result) => assert!(result.stats.flow_cost.is_some()),
+            _ => panic!("the job did not complete"),
+        }
+
+        let handle = worker.submit(
+            ids.new_id(),
+            (60, 50).into(),
+            (2, 3).into(),
+            &map,
+            LayerId(0),
+            1,
+        );
+        let status = handle.wait(Duration::from_secs(30));
+        assert!(matches!(status, JobStatus::Done(_)));
+    }
+
+    #[test]
+    fn cancelled_jobs_never_complete() {
+        let mut worker = PathWorker::new();
+        let mut ids = PathComputer::new();
+        worker.mode = Mode::WholeMap;
+        let map = Map::new(1024);
+        let handle = worker.submit(
+            ids.new_id(),
+            (0, 0).into(),
+            (1023, 1023).into(),
+            &map,
+            LayerId(0),
+            1,
+        );
+        handle.cancel();
+        assert!(matches!(handle.poll(), JobStatus::Cancelled));
+        let status = handle.wait(Duration::from_secs(30));
+        assert!(matches!(status, JobStatus::Cancelled));
+    }
+
+    #[test]
+    fn raising_the_priority_lets_a_job_go_first() {
+        let mut worker = PathWorker::new();
+        let mut ids = PathComputer::new();
+        let map = Map::new(1024);
+        // Long enough to still be pending once the quick job is done
+        worker.mode = Mode::WholeMap;
+        let slow = worker.submit(
+            ids.new_id(),
+            (0, 0).into(),
+            (1023, 1023).into(),
+            &map,
+            LayerId(0),
+            1,
+        );
+        worker.mode = Mode::Hierarchical;
+        let quick = worker.submit(
+            ids.new_id(),
+            (2, 3).into(),
+            (60, 50).into(),
+            &map,
+            LayerId(0),
+            1,
+        );
+        quick.set_priority(-1);
+
+        // The quick job waits for the slow one, until it is put ahead of it
+        thread::sleep(Duration::from_millis(100));
+        assert!(matches!(quick.poll(), JobStatus::Pending));
+        quick.set_priority(1);
+        assert_eq!(quick.priority(), 1);
+        assert!(matches!(poll_until_done(&quick), JobStatus::Done(_)));
+        assert!(matches!(slow.poll(), JobStatus::Pending));
+        slow.cancel();
+    }
+}
@@ -0,0 +1,259 @@
+use crate::chunk;
+use crate::field::{CellPos, Field, Topology};
+use crate::flowfield::GRID_SIZE;
+use crate::mapgen;
+use crate::pathfinding::{self, FullPathCompute, RequestId};
+use std::collections::HashMap;
+use std::fmt;
+
+// Where the cost of a world too large to hold comes from, a chunk at a time. The same chunk must
+// always be loaded with the same cost, it can be evicted and loaded again.
+pub trait ChunkProvider: Send {
+    // Cells from min, GRID_SIZE square except on the far edges of the world
+    fn load(&mut self, min: &CellPos, width: usize, height: usize) -> Field<u8>;
+}
+
+// The noise terrain of mapgen, generated on demand
+pub struct NoiseProvider {
+    pub seed: u64,
+}
+
+impl ChunkProvider for NoiseProvider {
+    fn load(&mut self, min: &CellPos, width: usize, height: usize) -> Field<u8> {
+        Field::from_fn(width, height, |local| {
+            mapgen::noise_at(self.seed, min.i + local.i, min.j + local.j)
+        })
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Stats {
+    pub loads: usize,
+    pub evictions: usize,
+    // Most chunks loaded at once
+    pub peak: usize,
+}
+
+// Cost of a world loaded by chunks, aligned to the sectors like the ones of Chunks. Past the
+// capacity, the chunks used the least recently are evicted.
+pub struct World {
+    pub width: usize,
+    pub height: usize,
+    pub topology: Topology,
+    pub capacity: usize,
+    pub stats: Stats,
+    provider: Box<dyn ChunkProvider>,
+    // With the time of their last use
+    loaded: HashMap<CellPos, (Field<u8>, u64)>,
+    clock: u64,
+}
+
+// The provider has nothing to show
+impl fmt::Debug for World {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("World")
+            .field("width", &self.width)
+            .field("height", &self.height)
+            .field("loaded", &self.loaded.len())
+            .finish()
+    }
+}
+
+impl World {
+    pub fn new(
+        width: usize,
+        height: usize,
+        topology: Topology,
+        capacity: usize,
+        provider: Box<dyn ChunkProvider>,
+    ) -> World {
+        World {
+            width,
+            height,
+            topology,
+            capacity,
+            stats: Stats::default(),
+            provider,
+            loaded: HashMap::new(),
+            clock: 0,
+        }
+    }
+
+    pub fn loaded(&self) -> usize {
+        self.loaded.len()
+    }
+
+    fn chunk(&mut self, chunk: &CellPos) -> &Field<u8> {
+        self.clock += 1;
+        if !self.loaded.contains_key(chunk) {
+            self.evict();
+            let min = CellPos {
+                i: chunk.i * GRID_SIZE,
+                j: chunk.j * GRID_SIZE,
+            };
+            let width = GRID_SIZE.min(self.width - min.i);
+            let height = GRID_SIZE.min(self.height - min.j);
+            let cells = self.provider.load(&min, width, height);
+            self.loaded.insert(*chunk, (cells, self.clock));
+            self.stats.loads += 1;
+            self.stats.peak = self.stats.peak.max(self.loaded.len());
+        }
+        let (cells, last_use) = self.loaded.get_mut(chunk).unwrap();
+        *last_use = self.clock;
+        cells
+    }
+
+    // Down to three quarters of the capacity once it is reached, so that the chunks are rarely
+    // sorted by last use
+    fn evict(&mut self) {
+        if self.loaded.len() < self.capacity {
+            return;
+        }
+        let mut uses: Vec<(u64, CellPos)> = self
+            .loaded
+            .iter()
+            .map(|(chunk, (_, last_use))| (*last_use, *chunk))
+            .collect();
+        uses.sort_unstable_by_key(|(last_use, _)| *last_use);
+        let evicted = uses.len() - self.capacity * 3 / 4;
+        for (_, chunk) in &uses[..evicted] {
+            self.loaded.remove(chunk);
+        }
+        self.stats.evictions += evicted;
+    }
+
+    pub fn cost_at(&mut self, cell_pos: &CellPos) -> u8 {
+        let local = CellPos {
+            i: cell_pos.i % GRID_SIZE,
+            j: cell_pos.j % GRID_SIZE,
+        };
+        *self.chunk(&chunk::of(cell_pos)).get(&local)
+    }
+
+    // Walls count as 255, so the coarse search avoids the chunks mostly walled
+    pub fn mean_cost(&mut self, chunk: &CellPos) -> i32 {
+        let cells = self.chunk(chunk);
+        let sum: i32 = cells.iter().map(|cost| *cost as i32).sum();
+        sum / (cells.width * cells.height) as i32
+    }
+}
+
+// For the UI, while a path is computed and once it is
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Progress {
+    pub id: Option<RequestId>,
+    pub loaded: usize,
+    pub chunks: Stats,
+    // Chunks expanded by the coarse search, then cells by the A* in its corridor
+    pub coarse: usize,
+    pub expanded: usize,
+    pub stats: Option<pathfinding::Stats>,
+    // Cell of the agent walking the path, and its cost to the goal
+    pub walker: Option<(CellPos, Option<i32>)>,
+}
+
+impl Progress {
+    // Nothing is known of a request computed on the background worker until it is done
+    pub fn of(world: &World, compute: Option<&FullPathCompute>) -> Progress {
+        let (coarse, expanded) = match compute {
+            Some(FullPathCompute::Coarse { coarse, fine }) => {
+                (coarse.stats().expanded, fine.stats().expanded)
+            }
+            Some(compute) => compute.stats().map_or((0, 0), |stats| {
+                (stats.coarse.expanded, stats.astar.expanded)
+            }),
+            None => (0, 0),
+        };
+        let stats = match compute {
+            Some(FullPathCompute::FlowFieldComputed(result)) => Some(result.stats),
+            _ => None,
+        };
+        Progress {
+            loaded: world.loaded(),
+            chunks: world.stats,
+            coarse,
+            expanded,
+            stats,
+            ..Progress::default()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::astar::{AStarCompute, Search};
+    use crate::source::CostSource;
+    use std::collections::HashSet;
+    use std::sync::{Arc, Mutex};
+
+    // The noise terrain, recording the chunks loaded
+    struct Recording {
+        noise: NoiseProvider,
+        loads: Arc<Mutex<Vec<CellPos>>>,
+    }
+
+    impl ChunkProvider for Recording {
+        fn load(&mut self, min: &CellPos, width: usize, height: usize) -> Field<u8> {
+            let chunk = chunk::of(min);
+            self.loads.lock().unwrap().push(chunk);
+            self.noise.load(min, width, height)
+        }
+    }
+
+    #[test]
+    fn crossing_loads_only_the_corridor() {
+        let loads = Arc::new(Mutex::new(Vec::new()));
+        let provider = Recording {
+            noise: NoiseProvider { seed: 3 },
+            loads: loads.clone(),
+        };
+        let capacity = 64;
+        let world = World::new(256, 256, Topology::default(), capacity, Box::new(provider));
+        let world = Arc::new(Mutex::new(world));
+        let (from, to) = ((2, 2).into(), (250, 250).into());
+        let mut compute = FullPathCompute::across_world(from, to, world.clone(), Search::AStar);
+
+        // Once the coarse path is found, the cells are searched in the chunks around it only
+        let mut corridor: Option<Arc<HashSet<CellPos>>> = None;
+        while !compute.is_computed() {
+            compute = compute.step();
+            if let FullPathCompute::Coarse {
+                coarse: AStarCompute::Computed(coarse),
+                fine:
+                    AStarCompute::InitialData {
+                        cost:
+                            CostSource::World {
+                                corridor: Some(fine_corridor),
+                                ..
+                            },
+                        ..
+                    },
+                ..
+            } = &compute
+            {
+                if corridor.is_none() {
+                    corridor = Some(fine_corridor.clone());
+                    // The coarse search reads at most the eight chunks around each one it expands
+                    let mut loads = loads.lock().unwrap();
+                    assert!(loads.len() <= 8 * coarse.stats.expanded);
+                    assert!(loads.len() < 32 * 32 / 4);
+                    loads.clear();
+                }
+            }
+        }
+
+        let corridor = corridor.unwrap();
+        let loads = loads.lock().unwrap();
+        assert!(!loads.is_empty());
+        assert!(loads.iter().all(|chunk| corridor.contains(chunk)));
+        assert!(corridor.len() < 32 * 32);
+        assert!(world.lock().unwrap().stats.peak <= capacity);
+        match compute {
+            FullPathCompute::FlowFieldComputed(result) => {
+                assert!(result.stats.flow_cost.is_some())
+            }
+            _ => unreachable!(),
+        }
+    }
+}